@@ -0,0 +1,421 @@
+use std::fmt;
+
+use crate::shell::Shell;
+
+/// Something wrong with an arithmetic expression, found while parsing or evaluating it.
+#[derive(Debug, PartialEq)]
+pub enum ArithError {
+    /// The expression doesn't parse, with the part of it where things went wrong
+    Syntax(String),
+    InvalidNumber(String),
+    DivisionByZero,
+    NegativeExponent,
+    /// A variable's value refers back to itself
+    TooDeep,
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithError::Syntax(rest) if rest.is_empty() => write!(f, "syntax error: expression is incomplete"),
+            ArithError::Syntax(rest) => write!(f, "syntax error in expression (error token is \"{rest}\")"),
+            ArithError::InvalidNumber(number) => write!(f, "invalid number: {number}"),
+            ArithError::DivisionByZero => write!(f, "division by zero"),
+            ArithError::NegativeExponent => write!(f, "exponent less than 0"),
+            ArithError::TooDeep => write!(f, "expression recursion level exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for ArithError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    /// An operator or a parenthesis
+    Op(&'static str),
+}
+
+/// Every operator, longest first so that e.g. "<<=" isn't read as "<" then "<="
+const OPERATORS: &[&str] = &[
+    "**=", "<<=", ">>=", "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "!", "~", "&", "^", "|",
+    "?", ":", "=", ",", "(", ")",
+];
+
+const ASSIGNMENTS: &[&str] = &["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=", "**="];
+
+/// The binary operators, from the loosest binding to the tightest (apart from `**`)
+const BINARY_LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// How many variables can refer to other variables before we give up
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    /// `cond ? then : otherwise`
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `name = value`, or with the operator before the '=' for things like `+=`
+    Assign(String, &'static str, Box<Expr>),
+    /// `++name` or `name--` and so on, adding `delta`
+    Step { name: String, delta: i64, prefix: bool },
+}
+
+/// Evaluates the (already expanded) arithmetic expression `text`, with variables from `shell`.
+pub fn evaluate(text: &str, shell: &mut Shell) -> Result<i64, ArithError> {
+    evaluate_at_depth(text, shell, 0)
+}
+
+fn evaluate_at_depth(text: &str, shell: &mut Shell, depth: usize) -> Result<i64, ArithError> {
+    if depth > MAX_DEPTH {
+        return Err(ArithError::TooDeep);
+    }
+
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+
+    let mut parser = ExprParser { text, tokens, pos: 0 };
+    let expr = parser.parse_comma()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error());
+    }
+
+    Evaluator { shell, depth }.eval(&expr)
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, ArithError> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+
+    loop {
+        rest = rest.trim_start();
+        let at = text.len() - rest.len();
+        let Some(c) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '#' | '_' | '@')))
+                .unwrap_or(rest.len());
+            tokens.push((Token::Num(parse_number(&rest[..len])?), at));
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push((Token::Name(rest[..len].to_string()), at));
+            len
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| ArithError::Syntax(rest.to_string()))?;
+            tokens.push((Token::Op(op), at));
+            op.len()
+        };
+
+        rest = &rest[len..];
+    }
+}
+
+/// Parses a number like `42`, `0x2a`, `052` or `16#2a`.
+fn parse_number(number: &str) -> Result<i64, ArithError> {
+    let invalid = || ArithError::InvalidNumber(number.to_string());
+
+    let (base, digits) = if let Some((base, digits)) = number.split_once('#') {
+        let base = base.parse::<u32>().ok().filter(|base| (2..=64).contains(base)).ok_or_else(invalid)?;
+        (base, digits)
+    } else if let Some(digits) = number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        (16, digits)
+    } else if number.len() > 1 && number.starts_with('0') {
+        (8, &number[1..])
+    } else {
+        (10, number)
+    };
+
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+
+    digits.chars().try_fold(0i64, |value, c| {
+        // Past base 36, lowercase and uppercase letters mean different things
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base > 36 => c as u32 - 'A' as u32 + 36,
+            'A'..='Z' => c as u32 - 'A' as u32 + 10,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        Ok(value.wrapping_mul(base as i64).wrapping_add(digit as i64))
+    })
+}
+
+struct ExprParser<'t> {
+    text: &'t str,
+    /// Each token along with where it starts in `text`, for error messages
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let found = self.peek_op() == Some(op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), ArithError> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    /// A syntax error at the current token.
+    fn error(&self) -> ArithError {
+        let rest = match self.tokens.get(self.pos) {
+            Some(&(_, at)) => self.text[at..].trim_end(),
+            None => "",
+        };
+        ArithError::Syntax(rest.to_string())
+    }
+
+    fn parse_comma(&mut self) -> Result<Expr, ArithError> {
+        let mut expr = self.parse_assignment()?;
+        while self.eat_op(",") {
+            let next = self.parse_assignment()?;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(next));
+        }
+        Ok(expr)
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expr, ArithError> {
+        if let (Some((Token::Name(name), _)), Some((Token::Op(op), _))) =
+            (self.tokens.get(self.pos), self.tokens.get(self.pos + 1))
+        {
+            if let Some(op) = ASSIGNMENTS.iter().find(|assignment| *assignment == op) {
+                let name = name.clone();
+                self.pos += 2;
+                let value = self.parse_assignment()?;
+                return Ok(Expr::Assign(name, op.trim_end_matches('='), Box::new(value)));
+            }
+        }
+
+        self.parse_conditional()
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr, ArithError> {
+        let cond = self.parse_binary(0)?;
+        if !self.eat_op("?") {
+            return Ok(cond);
+        }
+
+        let then = self.parse_comma()?;
+        self.expect_op(":")?;
+        let otherwise = self.parse_assignment()?;
+        Ok(Expr::Conditional(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ArithError> {
+        let Some(ops) = BINARY_LEVELS.get(level) else {
+            return self.parse_power();
+        };
+
+        let mut expr = self.parse_binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| ops.contains(op)) {
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+
+    fn parse_power(&mut self) -> Result<Expr, ArithError> {
+        let base = self.parse_unary()?;
+        if self.eat_op("**") {
+            // `**` groups from the right
+            let exponent = self.parse_power()?;
+            return Ok(Expr::Binary("**", Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ArithError> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let Some(Token::Name(name)) = self.peek().cloned() else {
+                    return Err(self.error());
+                };
+                self.pos += 1;
+                let delta = if op == "++" { 1 } else { -1 };
+                Ok(Expr::Step { name, delta, prefix: true })
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.pos += 1;
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary(op, Box::new(operand)))
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, ArithError> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error());
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Num(n) => Ok(Expr::Num(n)),
+            Token::Name(name) => match self.peek_op() {
+                Some(op @ ("++" | "--")) => {
+                    self.pos += 1;
+                    let delta = if op == "++" { 1 } else { -1 };
+                    Ok(Expr::Step { name, delta, prefix: false })
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            Token::Op("(") => {
+                let expr = self.parse_comma()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            Token::Op(_) => {
+                self.pos -= 1;
+                Err(self.error())
+            }
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    shell: &'a mut Shell,
+    /// How many variables deep we are, since a variable's value can be an expression too
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, ArithError> {
+        Ok(match expr {
+            Expr::Num(n) => *n,
+            Expr::Var(name) => self.var(name)?,
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                }
+            }
+            // These don't evaluate their right-hand side unless they need to
+            Expr::Binary("&&", lhs, rhs) => (self.eval(lhs)? != 0 && self.eval(rhs)? != 0) as i64,
+            Expr::Binary("||", lhs, rhs) => (self.eval(lhs)? != 0 || self.eval(rhs)? != 0) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                apply(op, lhs, rhs)?
+            }
+            Expr::Conditional(cond, then, otherwise) => {
+                if self.eval(cond)? != 0 {
+                    self.eval(then)?
+                } else {
+                    self.eval(otherwise)?
+                }
+            }
+            Expr::Assign(name, op, value) => {
+                let mut value = self.eval(value)?;
+                if !op.is_empty() {
+                    value = apply(op, self.var(name)?, value)?;
+                }
+                self.shell.set_var(name, value.to_string());
+                value
+            }
+            Expr::Step { name, delta, prefix } => {
+                let old = self.var(name)?;
+                let new = old.wrapping_add(*delta);
+                self.shell.set_var(name, new.to_string());
+                if *prefix {
+                    new
+                } else {
+                    old
+                }
+            }
+        })
+    }
+
+    /// The value of a variable, which can itself be an expression.
+    fn var(&mut self, name: &str) -> Result<i64, ArithError> {
+        match self.shell.var(name) {
+            Some(value) if !value.trim().is_empty() => {
+                let value = value.to_string();
+                evaluate_at_depth(&value, self.shell, self.depth + 1)
+            }
+            // Unset and empty variables are 0
+            _ => Ok(0),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, ArithError> {
+    Ok(match op {
+        "," => rhs,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err(ArithError::DivisionByZero),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" if rhs < 0 => return Err(ArithError::NegativeExponent),
+        "**" => lhs.wrapping_pow(rhs.min(u32::MAX as i64) as u32),
+        _ => unreachable!("{op} isn't a binary operator"),
+    })
+}
@@ -0,0 +1,140 @@
+use crate::lexer::WordPart;
+
+/// A word split up for brace expansion, where only unquoted text can have braces that count.
+#[derive(Debug, Clone)]
+enum Item {
+    /// A character of unquoted text
+    Char(char),
+    /// Anything else, which is carried along as it is
+    Part(WordPart),
+}
+
+/// Expands the braces in a word, like `src/{bin,lib}` or `file{1..3}`, into the words they stand for.
+///
+/// This happens before any other expansion, so what's in the braces is never the result of one.
+/// Braces without a `,` or a range between them (like `{}`) are left alone.
+pub fn expand_braces(parts: Vec<WordPart>) -> Vec<Vec<WordPart>> {
+    let has_brace = parts.iter().any(|part| matches!(part, WordPart::Literal(text) if text.contains('{')));
+    if !has_brace {
+        return vec![parts];
+    }
+
+    let items = parts
+        .into_iter()
+        .flat_map(|part| match part {
+            WordPart::Literal(text) => text.chars().map(Item::Char).collect(),
+            part => vec![Item::Part(part)],
+        })
+        .collect::<Vec<_>>();
+
+    expand(&items).into_iter().map(into_parts).collect()
+}
+
+fn expand(items: &[Item]) -> Vec<Vec<Item>> {
+    let opens = items.iter().enumerate().filter(|(_, item)| matches!(item, Item::Char('{')));
+
+    for (open, _) in opens {
+        let Some((close, commas)) = find_close(items, open) else {
+            continue;
+        };
+
+        let alternatives = if commas.is_empty() {
+            match range(&items[open + 1..close]) {
+                Some(words) => words.iter().map(|word| word.chars().map(Item::Char).collect()).collect(),
+                // Not a brace expansion, but there could still be one inside it
+                None => continue,
+            }
+        } else {
+            let bounds = [open].into_iter().chain(commas).chain([close]).collect::<Vec<_>>();
+            bounds.windows(2).map(|bounds| items[bounds[0] + 1..bounds[1]].to_vec()).collect::<Vec<_>>()
+        };
+
+        // The braces before this one weren't expansions, but any in the alternatives
+        // or after them might be
+        let mut words = Vec::new();
+        for mut rest in alternatives {
+            rest.extend_from_slice(&items[close + 1..]);
+            for expanded in expand(&rest) {
+                let mut word = items[..open].to_vec();
+                word.extend(expanded);
+                words.push(word);
+            }
+        }
+        return words;
+    }
+
+    vec![items.to_vec()]
+}
+
+/// Finds the `}` matching the `{` at `open`, along with the `,`s between them that
+/// aren't inside any other braces.
+fn find_close(items: &[Item], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+
+    for (i, item) in items.iter().enumerate().skip(open + 1) {
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') if depth == 0 => return Some((i, commas)),
+            Item::Char('}') => depth -= 1,
+            Item::Char(',') if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The words a range like `1..10`, `a..e` or `01..20..2` stands for.
+fn range(items: &[Item]) -> Option<Vec<String>> {
+    let text = items
+        .iter()
+        .map(|item| match item {
+            Item::Char(c) => Some(*c),
+            Item::Part(_) => None,
+        })
+        .collect::<Option<String>>()?;
+
+    let (start, end, step) = match text.split("..").collect::<Vec<_>>()[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None,
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // A leading zero on either end pads all the numbers to the same width
+        let padded = |n: &str| n.trim_start_matches('-').starts_with('0') && n.trim_start_matches('-').len() > 1;
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        let numbers = steps(first, last, step);
+        return Some(numbers.map(|n| format!("{n:0width$}")).collect());
+    }
+
+    let mut chars = (start.chars(), end.chars());
+    match (chars.0.next(), chars.0.next(), chars.1.next(), chars.1.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => {
+            let chars = steps(first as i64, last as i64, step);
+            Some(chars.filter_map(|c| char::from_u32(c as u32)).map(String::from).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Counts from `first` towards `last` (whichever way that is) by `step`, including `last`
+/// if it's landed on.
+fn steps(first: i64, last: i64, step: u64) -> impl Iterator<Item = i64> {
+    let count = first.abs_diff(last) / step;
+    let step = if first <= last { step as i64 } else { -(step as i64) };
+    (0..=count as i64).map(move |i| first + i * step)
+}
+
+/// Puts a word back together from its `Item`s.
+fn into_parts(items: Vec<Item>) -> Vec<WordPart> {
+    let mut parts = Vec::new();
+    for item in items {
+        match (parts.last_mut(), item) {
+            (Some(WordPart::Literal(text)), Item::Char(c)) => text.push(c),
+            (_, Item::Char(c)) => parts.push(WordPart::Literal(c.to_string())),
+            (_, Item::Part(part)) => parts.push(part),
+        }
+    }
+    parts
+}
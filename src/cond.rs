@@ -0,0 +1,262 @@
+use std::fs;
+use std::os::fd::RawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use crate::parser::{Arg, CondExpr};
+use crate::safe_wrappers;
+use crate::shell::{Jump, Shell};
+
+/// The tests that take one operand, like `-f path`.
+const UNARY_OPS: [&str; 20] = [
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-L", "-n", "-p", "-r", "-s", "-S", "-t", "-u", "-w", "-x",
+    "-z",
+];
+
+/// The tests that go between two operands, like `a = b`.
+const BINARY_OPS: [&str; 14] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+pub fn is_unary_op(op: &str) -> bool {
+    UNARY_OPS.contains(&op)
+}
+
+pub fn is_binary_op(op: &str) -> bool {
+    BINARY_OPS.contains(&op)
+}
+
+/// Whether `operand` passes the one-operand test `op`.
+pub fn unary_test(op: &str, operand: &str) -> bool {
+    let metadata = fs::metadata(operand);
+    let file_type = metadata.as_ref().map(|metadata| metadata.file_type());
+    let mode = metadata.as_ref().map_or(0, |metadata| metadata.mode());
+
+    match op {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-a" | "-e" => metadata.is_ok(),
+        "-f" => file_type.is_ok_and(|file_type| file_type.is_file()),
+        "-d" => file_type.is_ok_and(|file_type| file_type.is_dir()),
+        "-b" => file_type.is_ok_and(|file_type| file_type.is_block_device()),
+        "-c" => file_type.is_ok_and(|file_type| file_type.is_char_device()),
+        "-p" => file_type.is_ok_and(|file_type| file_type.is_fifo()),
+        "-S" => file_type.is_ok_and(|file_type| file_type.is_socket()),
+        // Unlike the others, these don't follow a symlink
+        "-h" | "-L" => fs::symlink_metadata(operand).is_ok_and(|metadata| metadata.file_type().is_symlink()),
+        "-s" => metadata.is_ok_and(|metadata| metadata.len() > 0),
+        "-u" => mode & 0o4000 != 0,
+        "-g" => mode & 0o2000 != 0,
+        "-k" => mode & 0o1000 != 0,
+        "-r" => safe_wrappers::access(operand, libc::R_OK),
+        "-w" => safe_wrappers::access(operand, libc::W_OK),
+        "-x" => safe_wrappers::access(operand, libc::X_OK),
+        "-t" => operand.trim().parse::<RawFd>().is_ok_and(safe_wrappers::isatty),
+        _ => false,
+    }
+}
+
+/// Whether `left` and `right` pass the two-operand test `op`, or why they can't be compared.
+pub fn binary_test(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    match op {
+        "=" | "==" => Ok(left == right),
+        "!=" => Ok(left != right),
+        "<" => Ok(left < right),
+        ">" => Ok(left > right),
+        "-nt" | "-ot" | "-ef" => Ok(compare_files(left, op, right)),
+        _ => Ok(compare_ints(parse_int(left)?, op, parse_int(right)?)),
+    }
+}
+
+fn parse_int(text: &str) -> Result<i64, String> {
+    text.trim().parse().map_err(|_| format!("{text}: integer expression expected"))
+}
+
+/// Compares two integers with one of the operators like `-lt`.
+pub fn compare_ints(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        "-ge" => left >= right,
+        _ => false,
+    }
+}
+
+/// `left -nt right` (newer than), `-ot` (older than) or `-ef` (the same file).
+///
+/// A file that exists is newer than one that doesn't.
+fn compare_files(left: &str, op: &str, right: &str) -> bool {
+    let (left, right) = (fs::metadata(left).ok(), fs::metadata(right).ok());
+    let modified = |metadata: &fs::Metadata| (metadata.mtime(), metadata.mtime_nsec());
+
+    match (op, left, right) {
+        ("-nt", Some(left), Some(right)) => modified(&left) > modified(&right),
+        ("-ot", Some(left), Some(right)) => modified(&left) < modified(&right),
+        ("-nt", left, _) => left.is_some(),
+        ("-ot", _, right) => right.is_some(),
+        ("-ef", Some(left), Some(right)) => (left.dev(), left.ino()) == (right.dev(), right.ino()),
+        _ => false,
+    }
+}
+
+/// Evaluates the arguments to `test` (or `[`, without its `]`).
+///
+/// Like POSIX says, up to four arguments are taken by how many there are, so that
+/// something like `test ! = x` compares strings rather than failing. Only past that
+/// do `!`, `-a`, `-o` and parentheses always mean what they say.
+pub fn test(args: &[String]) -> Result<bool, String> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    test_args(&args)
+}
+
+fn test_args(args: &[&str]) -> Result<bool, String> {
+    match *args {
+        [] => Ok(false),
+        [arg] => Ok(!arg.is_empty()),
+        ["!", arg] => Ok(arg.is_empty()),
+        [op, operand] if is_unary_op(op) => Ok(unary_test(op, operand)),
+        [op, _] => Err(format!("{op}: unary operator expected")),
+        [left, op, right] if is_binary_op(op) => binary_test(left, op, right),
+        ["!", ..] if args.len() <= 4 => test_args(&args[1..]).map(|result| !result),
+        ["(", .., ")"] if args.len() <= 4 => test_args(&args[1..args.len() - 1]),
+        _ => {
+            let mut parser = TestParser { args, pos: 0 };
+            let result = parser.parse_or()?;
+            match parser.args.get(parser.pos) {
+                Some(arg) => Err(format!("{arg}: too many arguments")),
+                None => Ok(result),
+            }
+        }
+    }
+}
+
+/// Evaluates `test`'s arguments as an expression with `!`, `-a`, `-o` and parentheses.
+struct TestParser<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.pos)?;
+        self.pos += 1;
+        Some(arg)
+    }
+
+    fn eat(&mut self, arg: &str) -> bool {
+        let found = self.args.get(self.pos) == Some(&arg);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn parse_or(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_and()?;
+        while self.eat("-o") {
+            // Both sides get parsed, even if the answer's already known
+            result |= self.parse_and()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_not()?;
+        while self.eat("-a") {
+            result &= self.parse_not()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<bool, String> {
+        if self.eat("!") {
+            return Ok(!self.parse_not()?);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<bool, String> {
+        let Some(arg) = self.next() else {
+            return Err("argument expected".to_string());
+        };
+
+        if arg == "(" {
+            let result = self.parse_or()?;
+            if !self.eat(")") {
+                return Err("`)' expected".to_string());
+            }
+            return Ok(result);
+        }
+
+        match self.args[self.pos..] {
+            [op, right, ..] if is_binary_op(op) => {
+                self.pos += 2;
+                binary_test(arg, op, right)
+            }
+            [operand, ..] if is_unary_op(arg) => {
+                self.pos += 1;
+                Ok(unary_test(arg, operand))
+            }
+            _ => Ok(!arg.is_empty()),
+        }
+    }
+}
+
+impl Shell {
+    /// Evaluates the expression in `[[ ]]`, whose words are expanded without being split
+    /// or globbed.
+    pub fn eval_cond(&mut self, expr: &CondExpr) -> Result<bool, Jump> {
+        match expr {
+            CondExpr::Word(word) => Ok(!self.expand_word(word)?.is_empty()),
+            CondExpr::Unary { op, operand } => Ok(unary_test(op, &self.expand_word(operand)?)),
+            CondExpr::Binary { left, op, right } => self.eval_cond_binary(left, op, right),
+            CondExpr::Not(expr) => Ok(!self.eval_cond(expr)?),
+            CondExpr::And(left, right) => Ok(self.eval_cond(left)? && self.eval_cond(right)?),
+            CondExpr::Or(left, right) => Ok(self.eval_cond(left)? || self.eval_cond(right)?),
+        }
+    }
+
+    fn eval_cond_binary(&mut self, left: &Arg, op: &str, right: &Arg) -> Result<bool, Jump> {
+        match op {
+            // The right-hand side is a pattern, unless it's quoted
+            "=" | "==" | "!=" => {
+                let left = self.expand_word(left)?;
+                let matched = self.expand_pattern(right)?.matches(&left);
+                Ok(matched == (op != "!="))
+            }
+            "=~" => {
+                let left = self.expand_word(left)?;
+                let regex = self.expand_regex(right)?;
+                self.match_regex(&left, &regex)
+            }
+            // Unlike with `test`, the operands are arithmetic expressions
+            "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                let left = self.eval_arith(left)?;
+                Ok(compare_ints(left, op, self.eval_arith(right)?))
+            }
+            _ => {
+                let left = self.expand_word(left)?;
+                let right = self.expand_word(right)?;
+                Ok(binary_test(&left, op, &right).unwrap_or(false))
+            }
+        }
+    }
+
+    /// Matches `text` against `regex`, putting what matched and what each group in it
+    /// matched in `BASH_REMATCH`.
+    fn match_regex(&mut self, text: &str, regex: &str) -> Result<bool, Jump> {
+        match safe_wrappers::regex_match(regex, text) {
+            Ok(groups) => {
+                let matched = groups.is_some();
+                self.set_array("BASH_REMATCH", groups.unwrap_or_default());
+                Ok(matched)
+            }
+            Err(message) => {
+                eprintln!("{}: {regex}: {message}", self.name);
+                Err(Jump::Error(2))
+            }
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use crate::arith;
+use crate::glob::{self, Pattern};
+use crate::parser::{is_name, Arg, MissingAction, Param, ParamOp, ReplaceMode};
+use crate::safe_wrappers;
+use crate::shell::{Jump, Shell};
+
+/// A piece of a word partway through expansion.
+struct Piece {
+    text: String,
+    /// Whether this was quoted, so it's taken just as it is
+    quoted: bool,
+    /// Whether this came from an expansion (rather than being written out), so it can be
+    /// split into more fields if it isn't quoted
+    expanded: bool,
+}
+
+/// The pieces of one field (future argument) that a word expands to.
+type Field = Vec<Piece>;
+
+/// Adds `text` to the end of the last field.
+fn push_piece(fields: &mut [Field], text: String, quoted: bool, expanded: bool) {
+    if let Some(field) = fields.last_mut() {
+        field.push(Piece { text, quoted, expanded });
+    }
+}
+
+fn field_text(field: Field) -> String {
+    field.into_iter().map(|piece| piece.text).collect()
+}
+
+/// The text of a field as a pattern, with the quoted pieces escaped.
+fn pattern_text(field: &Field) -> String {
+    field
+        .iter()
+        .map(|piece| if piece.quoted { glob::escape(&piece.text) } else { piece.text.clone() })
+        .collect()
+}
+
+/// The text of a field as an extended regular expression, with the quoted pieces escaped.
+fn regex_text(field: &Field) -> String {
+    let mut regex = String::new();
+    for piece in field {
+        if !piece.quoted {
+            regex.push_str(&piece.text);
+            continue;
+        }
+        for c in piece.text.chars() {
+            if "\\^$.|?*+()[]{}".contains(c) {
+                regex.push('\\');
+            }
+            regex.push(c);
+        }
+    }
+    regex
+}
+
+/// Splits `NAME[subscript]` into the name of the array and the subscript.
+fn split_subscript(name: &str) -> Option<(&str, &str)> {
+    let (array, rest) = name.split_once('[')?;
+    Some((array, rest.strip_suffix(']')?))
+}
+
+impl Shell {
+    /// Expands the words of a command into the strings it's run with.
+    pub fn expand_words(&mut self, args: &[Arg]) -> Result<Vec<String>, Jump> {
+        let mut words = Vec::new();
+        for arg in args {
+            let fields = self.expand_fields(arg)?;
+            for field in fields.into_iter().flat_map(|field| split_field(field, self.ifs())) {
+                // Unquoted expansions that come out empty don't leave a word behind
+                if field.iter().all(|piece| !piece.quoted && piece.text.is_empty()) {
+                    continue;
+                }
+                self.expand_paths(field, &mut words)?;
+            }
+        }
+        Ok(words)
+    }
+
+    /// Adds the paths `field` matches to `words`, if it's a pattern, or else just its text.
+    fn expand_paths(&self, field: Field, words: &mut Vec<String>) -> Result<(), Jump> {
+        if !field.iter().any(|piece| !piece.quoted && glob::has_magic(&piece.text)) {
+            words.push(field_text(field));
+            return Ok(());
+        }
+
+        let paths = glob::glob(&pattern_text(&field), self.glob_options);
+        if !paths.is_empty() {
+            words.extend(paths);
+        } else if self.glob_options.failglob {
+            eprintln!("{}: no match: {}", self.name, field_text(field));
+            return Err(Jump::Error(1));
+        } else if !self.glob_options.nullglob {
+            words.push(field_text(field));
+        }
+        Ok(())
+    }
+
+    /// Expands a single word, where it can only be one string (like a variable's value).
+    pub fn expand_word(&mut self, arg: &Arg) -> Result<String, Jump> {
+        let fields = self.expand_fields(arg)?;
+        Ok(fields.into_iter().map(field_text).collect::<Vec<_>>().join(" "))
+    }
+
+    /// Expands a word to use as a pattern, where only the unquoted parts can be special.
+    pub fn expand_pattern(&mut self, arg: &Arg) -> Result<Pattern, Jump> {
+        let fields = self.expand_fields(arg)?;
+        let pattern = fields.iter().map(pattern_text).collect::<Vec<_>>().join(" ");
+        Ok(Pattern::new(&pattern))
+    }
+
+    /// Expands a word to use as an extended regular expression, where only the unquoted
+    /// parts can be special.
+    pub fn expand_regex(&mut self, arg: &Arg) -> Result<String, Jump> {
+        let fields = self.expand_fields(arg)?;
+        Ok(fields.iter().map(regex_text).collect::<Vec<_>>().join(" "))
+    }
+
+    fn expand_fields(&mut self, arg: &Arg) -> Result<Vec<Field>, Jump> {
+        let mut fields = vec![Vec::new()];
+        self.expand_into(arg, false, &mut fields)?;
+        Ok(fields)
+    }
+
+    /// Expands `arg` onto the end of `fields`, where it may start new ones.
+    fn expand_into(&mut self, arg: &Arg, quoted: bool, fields: &mut Vec<Field>) -> Result<(), Jump> {
+        let text = match arg {
+            Arg::Word(text) => text.clone(),
+            Arg::Variable(param) => {
+                if let (Some((values, at)), None) = (self.list_param(&param.name), &param.op) {
+                    expand_list(&values, at, quoted, self.ifs(), fields);
+                    return Ok(());
+                }
+                self.expand_param(param)?
+            }
+            Arg::Subshell(cmd) => self.command_output(cmd),
+            Arg::ProcSub { command, output } => self.process_substitution(command, *output)?,
+            Arg::Arith(expr) => self.eval_arith(expr)?.to_string(),
+            Arg::Tilde(prefix) => {
+                // Like a quoted expansion, what the `~` stands for is never split or globbed
+                push_piece(fields, self.expand_tilde(prefix), true, true);
+                return Ok(());
+            }
+            Arg::Quoted(args) => {
+                if args.is_empty() {
+                    // `""` is still a word, even with nothing in it
+                    push_piece(fields, String::new(), true, false);
+                }
+                for arg in args {
+                    self.expand_into(arg, true, fields)?;
+                }
+                return Ok(());
+            }
+            Arg::Concat(args) => {
+                for arg in args {
+                    self.expand_into(arg, quoted, fields)?;
+                }
+                return Ok(());
+            }
+        };
+
+        let expanded = !matches!(arg, Arg::Word(_));
+        push_piece(fields, text, quoted, expanded);
+        Ok(())
+    }
+
+    /// Expands and evaluates an arithmetic expression.
+    pub fn eval_arith(&mut self, expr: &Arg) -> Result<i64, Jump> {
+        let text = self.expand_word(expr)?;
+        arith::evaluate(&text, self).map_err(|e| {
+            eprintln!("{}: {}: {e}", self.name, text.trim());
+            Jump::Error(1)
+        })
+    }
+
+    /// The directory `~prefix` stands for, or just `~prefix` if there isn't one.
+    fn expand_tilde(&self, prefix: &str) -> String {
+        let dir = match prefix {
+            "" => self.var("HOME").map(str::to_string),
+            "+" => self.var("PWD").map(str::to_string),
+            "-" => self.var("OLDPWD").map(str::to_string),
+            user => safe_wrappers::getpwnam(user),
+        };
+        dir.unwrap_or_else(|| format!("~{prefix}"))
+    }
+
+    /// The values of `$@` or `$*`, or `${NAME[@]}` or `${NAME[*]}`, and whether it's an `@`,
+    /// or `None` if `name` isn't one of them.
+    fn list_param(&self, name: &str) -> Option<(Vec<String>, bool)> {
+        match name {
+            "@" | "*" => Some((self.positional.clone(), name == "@")),
+            _ => match split_subscript(name)? {
+                (array, subscript @ ("@" | "*")) => Some((self.array(array), subscript == "@")),
+                _ => None,
+            },
+        }
+    }
+
+    /// The value of the parameter called `name`, or `None` if it isn't set.
+    fn param_value(&self, name: &str) -> Option<String> {
+        match name {
+            "@" | "*" => (!self.positional.is_empty()).then(|| self.positional.join(" ")),
+            "#" => Some(self.positional.len().to_string()),
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(self.pid.to_string()),
+            // Nothing runs in the background yet, so there's never a last background job
+            "!" => None,
+            "0" => Some(self.name.clone()),
+            _ if name.contains('[') => {
+                let (array, subscript) = split_subscript(name)?;
+                let elements = self.array(array);
+                match subscript {
+                    "@" | "*" => (!elements.is_empty()).then(|| elements.join(" ")),
+                    index => elements.get(index.trim().parse::<usize>().ok()?).cloned(),
+                }
+            }
+            _ if name.starts_with(|c: char| c.is_ascii_digit()) => {
+                let index = name.parse::<usize>().ok()?.checked_sub(1)?;
+                self.positional.get(index).cloned()
+            }
+            _ => self.var(name).map(str::to_string),
+        }
+    }
+
+    fn expand_param(&mut self, param: &Param) -> Result<String, Jump> {
+        use ParamOp as PO;
+
+        let value = self.param_value(&param.name);
+        let Some(op) = &param.op else {
+            return Ok(value.unwrap_or_default());
+        };
+
+        let value = match op {
+            PO::Length => {
+                let length = match self.list_param(&param.name) {
+                    // The number of values, rather than the length of them all
+                    Some((values, _)) => values.len(),
+                    None => value.map_or(0, |value| value.chars().count()),
+                };
+                return Ok(length.to_string());
+            }
+            PO::Missing { action, colon, word } => {
+                let missing = value.as_ref().is_none_or(|value| *colon && value.is_empty());
+                return self.expand_missing(param, value, missing, *action, word);
+            }
+            _ => value.unwrap_or_default(),
+        };
+
+        Ok(match op {
+            PO::TrimPrefix { pattern, longest } => {
+                self.expand_pattern(pattern)?.trim_prefix(&value, *longest).to_string()
+            }
+            PO::TrimSuffix { pattern, longest } => {
+                self.expand_pattern(pattern)?.trim_suffix(&value, *longest).to_string()
+            }
+            PO::Replace { pattern, replacement, mode } => {
+                let pattern = self.expand_pattern(pattern)?;
+                let replacement = self.expand_word(replacement)?;
+                replace(&value, &pattern, &replacement, *mode)
+            }
+            PO::Length | PO::Missing { .. } => unreachable!("handled above"),
+        })
+    }
+
+    /// Expands `${VAR:-word}` and friends, where `value` is `VAR`'s value.
+    fn expand_missing(
+        &mut self,
+        param: &Param,
+        value: Option<String>,
+        missing: bool,
+        action: MissingAction,
+        word: &Arg,
+    ) -> Result<String, Jump> {
+        use MissingAction as MA;
+
+        match (action, missing) {
+            (MA::Default, true) | (MA::Alternative, false) => self.expand_word(word),
+            (MA::Alternative, true) => Ok(String::new()),
+            (MA::Assign, true) if !is_name(&param.name) => {
+                eprintln!("{}: ${}: cannot assign in this way", self.name, param.name);
+                Err(Jump::Error(1))
+            }
+            (MA::Assign, true) => {
+                let value = self.expand_word(word)?;
+                self.set_var(&param.name, value.clone());
+                Ok(value)
+            }
+            (MA::Error, true) => {
+                let message = match self.expand_word(word)? {
+                    message if !message.is_empty() => message,
+                    _ if value.is_some() => "parameter null or not set".to_string(),
+                    _ => "parameter not set".to_string(),
+                };
+                eprintln!("{}: {}: {message}", self.name, param.name);
+                Err(Jump::Error(1))
+            }
+            (MA::Default | MA::Assign | MA::Error, false) => Ok(value.unwrap_or_default()),
+        }
+    }
+}
+
+/// Expands `$@` (`at`) or `$*`, or the like for an array, into a field for each of `values`,
+/// except for `"$*"`, which joins them into one with the first character of `ifs`.
+fn expand_list(values: &[String], at: bool, quoted: bool, ifs: &str, fields: &mut Vec<Field>) {
+    if quoted && !at {
+        let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+        push_piece(fields, values.join(&separator), true, true);
+        return;
+    }
+
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            fields.push(Vec::new());
+        }
+        push_piece(fields, value.clone(), quoted, true);
+    }
+}
+
+/// Splits the unquoted expansions in `field` into more fields wherever there's a character
+/// from `ifs`.
+///
+/// A run of whitespace from `ifs` separates fields, but doesn't make any at the start or end.
+/// Any other character from `ifs` ends a field even if it's empty, soaking up the whitespace
+/// around it.
+fn split_field(field: Field, ifs: &str) -> Vec<Field> {
+    let is_space = |c: char| matches!(c, ' ' | '\t' | '\n') && ifs.contains(c);
+    let has_text = |field: &Field| field.iter().any(|piece| piece.quoted || !piece.text.is_empty());
+
+    let mut fields = vec![Vec::new()];
+    // Whether there's whitespace that will separate the current field from whatever comes next
+    let mut space_before = false;
+
+    for piece in field {
+        if piece.quoted || !piece.expanded || ifs.is_empty() {
+            if space_before {
+                fields.push(Vec::new());
+                space_before = false;
+            }
+            fields.last_mut().unwrap().push(piece);
+            continue;
+        }
+
+        let mut text = String::new();
+        for c in piece.text.chars() {
+            if is_space(c) {
+                space_before |= !text.is_empty() || has_text(fields.last().unwrap());
+                continue;
+            }
+
+            let delimiter = ifs.contains(c);
+            if space_before || delimiter {
+                let current = fields.last_mut().unwrap();
+                current.push(Piece { text: std::mem::take(&mut text), quoted: false, expanded: true });
+                if delimiter {
+                    // An empty quoted piece keeps the field from being dropped, even if it's empty
+                    current.push(Piece { text: String::new(), quoted: true, expanded: true });
+                }
+                fields.push(Vec::new());
+                space_before = false;
+            }
+            if !delimiter {
+                text.push(c);
+            }
+        }
+        fields.last_mut().unwrap().push(Piece { text, quoted: false, expanded: true });
+    }
+
+    fields
+}
+
+/// `${VAR/pattern/replacement}` and friends, where `value` is `VAR`'s value.
+fn replace(value: &str, pattern: &Pattern, replacement: &str, mode: ReplaceMode) -> String {
+    match mode {
+        ReplaceMode::First | ReplaceMode::All => {
+            let mut replaced = String::new();
+            let mut from = 0;
+            while let Some((start, end)) = pattern.find(value, from) {
+                replaced.push_str(&value[from..start]);
+                replaced.push_str(replacement);
+                from = end;
+                if mode == ReplaceMode::First {
+                    break;
+                }
+            }
+            replaced.push_str(&value[from..]);
+            replaced
+        }
+        ReplaceMode::Prefix => {
+            let rest = pattern.trim_prefix(value, true);
+            if rest.len() == value.len() && !pattern.matches("") {
+                value.to_string()
+            } else {
+                format!("{replacement}{rest}")
+            }
+        }
+        ReplaceMode::Suffix => {
+            let rest = pattern.trim_suffix(value, true);
+            if rest.len() == value.len() && !pattern.matches("") {
+                value.to_string()
+            } else {
+                format!("{rest}{replacement}")
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs;
+use std::path::Path;
+
+/// A shell pattern, like `*.rs` or `[a-z]?`.
+///
+/// A backslash makes the character after it match only itself, which is how quoted
+/// parts of a pattern are kept from being special.
+#[derive(Debug)]
+pub struct Pattern {
+    items: Vec<Item>,
+}
+
+#[derive(Debug)]
+enum Item {
+    Char(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyString,
+    /// `[...]`
+    Class { negated: bool, members: Vec<ClassMember> },
+}
+
+#[derive(Debug)]
+enum ClassMember {
+    Char(char),
+    Range(char, char),
+    /// `[:alpha:]` and friends
+    Named(fn(char) -> bool),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars = pattern.chars().collect::<Vec<_>>();
+        let mut items = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let item = match chars[i] {
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Item::Char(chars[i])
+                }
+                '?' => Item::AnyChar,
+                '*' => Item::AnyString,
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((class, len)) => {
+                        i += len;
+                        class
+                    }
+                    // A '[' with no ']' after it is just a '['
+                    None => Item::Char('['),
+                },
+                c => Item::Char(c),
+            };
+            items.push(item);
+            i += 1;
+        }
+
+        Pattern { items }
+    }
+
+    /// Whether all of `text` matches the pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        let text = text.chars().collect::<Vec<_>>();
+        // Where to pick up from if what came after the last '*' doesn't work out
+        let mut backtrack = None;
+        let (mut p, mut t) = (0, 0);
+
+        while t < text.len() {
+            match self.items.get(p) {
+                Some(Item::AnyString) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                }
+                Some(item) if item.matches(text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    // Let the '*' swallow one more character and try again
+                    Some((star, star_t)) => {
+                        backtrack = Some((star, star_t + 1));
+                        p = star + 1;
+                        t = star_t + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        self.items[p..].iter().all(|item| matches!(item, Item::AnyString))
+    }
+
+    /// `text` with the shortest (or `longest`) prefix matching the pattern removed.
+    pub fn trim_prefix<'t>(&self, text: &'t str, longest: bool) -> &'t str {
+        let mut ends = char_boundaries(text);
+        if longest {
+            ends.reverse();
+        }
+
+        match ends.into_iter().find(|&end| self.matches(&text[..end])) {
+            Some(end) => &text[end..],
+            None => text,
+        }
+    }
+
+    /// `text` with the shortest (or `longest`) suffix matching the pattern removed.
+    pub fn trim_suffix<'t>(&self, text: &'t str, longest: bool) -> &'t str {
+        let mut starts = char_boundaries(text);
+        if !longest {
+            starts.reverse();
+        }
+
+        match starts.into_iter().find(|&start| self.matches(&text[start..])) {
+            Some(start) => &text[..start],
+            None => text,
+        }
+    }
+
+    /// The byte range of the first longest non-empty match in `text` at or after `from`.
+    pub fn find(&self, text: &str, from: usize) -> Option<(usize, usize)> {
+        let boundaries = char_boundaries(text);
+
+        boundaries.iter().filter(|&&start| start >= from).find_map(|&start| {
+            boundaries
+                .iter()
+                .rev()
+                .take_while(|&&end| end > start)
+                .find(|&&end| self.matches(&text[start..end]))
+                .map(|&end| (start, end))
+        })
+    }
+}
+
+impl Item {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Item::Char(expected) => c == *expected,
+            Item::AnyChar => true,
+            Item::AnyString => false,
+            Item::Class { negated, members } => {
+                let found = members.iter().any(|member| match *member {
+                    ClassMember::Char(expected) => c == expected,
+                    ClassMember::Range(low, high) => (low..=high).contains(&c),
+                    ClassMember::Named(is_member) => is_member(c),
+                });
+                found != *negated
+            }
+        }
+    }
+}
+
+/// Parses the inside of a `[...]`, returning the class and how many characters it took up
+/// (including the ']', but not the '[').
+fn parse_class(chars: &[char]) -> Option<(Item, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut members = Vec::new();
+    // A ']' right at the start is part of the class rather than the end of it
+    let class_start = i;
+
+    loop {
+        let c = *chars.get(i)?;
+        match c {
+            ']' if i > class_start => return Some((Item::Class { negated, members }, i + 1)),
+            '[' if chars.get(i + 1) == Some(&':') => {
+                let name_len = chars[i + 2..].windows(2).position(|w| w == [':', ']'])?;
+                let name = chars[i + 2..i + 2 + name_len].iter().collect::<String>();
+                members.push(ClassMember::Named(named_class(&name)?));
+                i += name_len + 4;
+            }
+            _ => {
+                let (c, len) = match c {
+                    '\\' => (*chars.get(i + 1)?, 2),
+                    _ => (c, 1),
+                };
+                i += len;
+
+                // A '-' at the end is just a '-'
+                if chars.get(i) == Some(&'-') && chars.get(i + 1).is_some_and(|&c| c != ']') {
+                    let (high, len) = match chars[i + 1] {
+                        '\\' => (*chars.get(i + 2)?, 2),
+                        high => (high, 1),
+                    };
+                    members.push(ClassMember::Range(c, high));
+                    i += 1 + len;
+                } else {
+                    members.push(ClassMember::Char(c));
+                }
+            }
+        }
+    }
+}
+
+fn named_class(name: &str) -> Option<fn(char) -> bool> {
+    Some(match name {
+        "alnum" => |c| c.is_alphanumeric(),
+        "alpha" => |c| c.is_alphabetic(),
+        "blank" => |c| c == ' ' || c == '\t',
+        "cntrl" => |c| c.is_control(),
+        "digit" => |c| c.is_ascii_digit(),
+        "graph" => |c| !c.is_control() && !c.is_whitespace(),
+        "lower" => |c| c.is_lowercase(),
+        "print" => |c| !c.is_control(),
+        "punct" => |c| c.is_ascii_punctuation(),
+        "space" => |c| c.is_whitespace(),
+        "upper" => |c| c.is_uppercase(),
+        "xdigit" => |c| c.is_ascii_hexdigit(),
+        _ => return None,
+    })
+}
+
+/// Every byte offset in `text` that starts a character, plus the end.
+fn char_boundaries(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain([text.len()]).collect()
+}
+
+/// Escapes `text` so that, as a pattern, it only matches itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '?' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The `shopt` options that change how words are expanded into paths.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobOptions {
+    /// Names starting with `.` can match without the pattern starting with `.` too
+    pub dotglob: bool,
+    /// A pattern that matches nothing is an error
+    pub failglob: bool,
+    /// `**` on its own matches any number of directories
+    pub globstar: bool,
+    /// A pattern that matches nothing disappears, rather than being left as it is
+    pub nullglob: bool,
+}
+
+/// Whether `text` has any unescaped `*`, `?` or `[...]` that would make it a pattern.
+pub fn has_magic(text: &str) -> bool {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' => return true,
+            '[' if chars.as_str().contains(']') => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Removes the backslashes `escape` would have added.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// The paths matching `pattern`, in sorted order.
+///
+/// Each part of the pattern between `/`s is matched against the names in one directory.
+/// Names starting with `.` are left out unless that part of the pattern starts with `.`.
+pub fn glob(pattern: &str, options: GlobOptions) -> Vec<String> {
+    let components = pattern.split('/').collect::<Vec<_>>();
+    // Everything matched so far, each ending in a '/' unless it's the whole path
+    let mut paths = vec![String::new()];
+
+    for (i, &component) in components.iter().enumerate() {
+        let last = i == components.len() - 1;
+        let separator = if last { "" } else { "/" };
+        let mut matched = Vec::new();
+
+        for path in paths {
+            if options.globstar && component == "**" {
+                // Any number of directories, including none
+                if !last || !path.is_empty() {
+                    matched.push(path.clone());
+                }
+                for found in walk(&path, !last, options.dotglob) {
+                    matched.push(format!("{path}{found}{separator}"));
+                }
+            } else if has_magic(component) {
+                let pattern = Pattern::new(component);
+                let show_hidden = options.dotglob || component.starts_with('.');
+                for name in read_dir(&path, !last) {
+                    if (show_hidden || !name.starts_with('.')) && pattern.matches(&name) {
+                        matched.push(format!("{path}{name}{separator}"));
+                    }
+                }
+            } else {
+                matched.push(format!("{path}{}{separator}", unescape(component)));
+            }
+        }
+
+        paths = matched;
+    }
+
+    // Literal parts were taken on faith, so make sure they're really there
+    paths.retain(|path| Path::new(path).symlink_metadata().is_ok());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The names in the directory `path` (or the current one, if it's empty), leaving out
+/// anything that isn't a directory if `dirs_only`.
+fn read_dir(path: &str, dirs_only: bool) -> Vec<String> {
+    let dir = if path.is_empty() { "." } else { path };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| !dirs_only || entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect()
+}
+
+/// Everything under the directory `path`, relative to it, for `**`.
+fn walk(path: &str, dirs_only: bool, dotglob: bool) -> Vec<String> {
+    let mut found = Vec::new();
+    for name in read_dir(path, false) {
+        if !dotglob && name.starts_with('.') {
+            continue;
+        }
+
+        let full = format!("{path}{name}");
+        // Symlinks to directories aren't followed, so a loop can't go on forever
+        let is_dir = Path::new(&full).symlink_metadata().is_ok_and(|meta| meta.is_dir());
+        if is_dir || !dirs_only {
+            found.push(name.clone());
+        }
+        if is_dir {
+            let below = walk(&format!("{full}/"), dirs_only, dotglob);
+            found.extend(below.into_iter().map(|below| format!("{name}/{below}")));
+        }
+    }
+    found
+}
@@ -1,251 +1,646 @@
-use std::iter::Peekable;
-use std::str::Chars;
+use std::mem;
 
-use crate::parser::ParseError;
+use crate::parser::{is_name, CaseTerminator, MissingAction, Param, ParamOp, ParseError, ReplaceMode};
 
-#[derive(Debug)]
-struct UnmatchedDelimiterError;
+/// A range of byte offsets into the input, used to point at things in diagnostics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
 
 #[derive(Debug)]
 pub enum Token {
-    Word(String),
+    Word(Vec<WordPart>),
+    /// A command in parentheses at the start of a word
     SubShell(String),
-    Variable(String),
+    /// `(( expression ))`
+    Arith(Vec<WordPart>),
     Pipe,
     PipeBoth,
     RedirOut,
     RedirErr,
     RedirBoth,
+    /// `<<DELIMITER` or `<<-DELIMITER`, with the body of the here-document from the lines after it
+    HereDoc(Vec<WordPart>),
+    /// `<<< word`
+    HereString(Vec<WordPart>),
     AndThen,
     AndThenIf,
+    /// `;;`, `;&` or `;;&` at the end of a `case` arm
+    CaseEnd(CaseTerminator),
+    /// A `)` on its own, like after a `case` pattern
+    CloseParen,
+    Newline,
+}
+
+/// One piece of a word, which can be made of any mix of literal text, expansions and quotes.
+#[derive(Debug, Clone)]
+pub enum WordPart {
+    Literal(String),
+    /// `$NAME` or `${NAME...}`
+    Variable(Param<Vec<WordPart>>),
+    /// `$(command)`, with `offset` being where `command` starts in the source
+    SubShell { command: String, offset: usize },
+    /// `$(( expression ))`
+    Arith(Vec<WordPart>),
+    /// `<(command)`, or `>(command)` if `output` is where the command's input comes from
+    ProcSub { command: String, offset: usize, output: bool },
+    /// Parts that were quoted or escaped, so they're never split or globbed
+    Quoted(Vec<WordPart>),
+}
+
+/// Adds `part` to the end of `parts`, merging it into the last part where they're the same kind.
+fn push_part(parts: &mut Vec<WordPart>, part: WordPart) {
+    match (parts.last_mut(), part) {
+        (Some(WordPart::Literal(text)), WordPart::Literal(more)) => text.push_str(&more),
+        (Some(WordPart::Quoted(quoted)), WordPart::Quoted(more)) => {
+            for part in more {
+                push_part(quoted, part);
+            }
+        }
+        (_, part) => parts.push(part),
+    }
+}
+
+fn push_char(parts: &mut Vec<WordPart>, c: char) {
+    push_part(parts, WordPart::Literal(c.to_string()));
+}
+
+/// Whether a word ends where `rest` of the input starts, at an operator or whitespace.
+fn ends_word(rest: &str) -> bool {
+    rest.starts_with(|c: char| c.is_whitespace() || matches!(c, '|' | ';' | '>' | '&' | '(' | ')')) || rest.starts_with("<<")
 }
 
+/// Like `ends_word`, but for the regular expression after `=~`, where parentheses and
+/// single `|`s are part of the word.
+fn ends_regex(rest: &str) -> bool {
+    rest.starts_with(|c: char| c.is_whitespace() || c == ';') || rest.starts_with("&&") || rest.starts_with("||")
+}
+
+/// Whether `$c` is one of the special parameters, like `$?` or `$@`.
+fn is_special_param(c: char) -> bool {
+    matches!(c, '@' | '*' | '#' | '?' | '$' | '!')
+}
+
+#[derive(Clone)]
 pub struct Lexer<'a> {
-    chars: Peekable<Chars<'a>>,
+    input: &'a str,
+    /// Byte offset of the next character in `input`
+    pos: usize,
+    /// Added to every span we produce, for when `input` is a slice of some larger source
+    offset: usize,
+    /// Where the bodies of the here-documents on the current line end, which is where
+    /// the next line really starts
+    heredoc_end: Option<usize>,
+    /// Whether the last token was `=~`, so the next word is a regular expression
+    regex_next: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
+        Lexer::new_at(input, 0)
+    }
+
+    /// Makes a lexer for `input`, which starts `offset` bytes into the source spans refer to.
+    pub fn new_at(input: &'a str, offset: usize) -> Self {
         Lexer {
-            chars: input.chars().peekable(),
+            input,
+            pos: 0,
+            offset,
+            heredoc_end: None,
+            regex_next: false,
         }
     }
 
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Consumes `prefix` if the input continues with it.
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The span from byte `start` of our input up to the current position.
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(self.offset + start, self.offset + self.pos)
+    }
+
+    /// The span of the (single-byte) character at byte `at` of our input.
+    fn char_span(&self, at: usize) -> Span {
+        Span::new(self.offset + at, self.offset + at + 1)
+    }
+
+    /// Skips whitespace other than newlines, which separate commands.
+    ///
+    /// Escaped newlines just continue the line, so they're skipped too.
     fn skip_whitespace(&mut self) {
-        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        loop {
+            if self.peek().is_some_and(|c| c.is_whitespace() && c != '\n') {
+                self.bump();
+            } else if self.rest() == "\\\n" || !self.eat("\\\n") {
+                // A continuation with nothing after it is left for `lex_word` to complain about
+                break;
+            }
+        }
     }
 
-    fn lex_word(&mut self) -> Result<Token, ParseError> {
-        let mut word = String::new();
+    /// Skips a comment, which runs from a '#' at the start of a word to the end of the line.
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.bump();
+            }
+        }
+    }
+
+    fn lex_word(&mut self, regex: bool) -> Result<Token, ParseError> {
+        let parts = self.lex_word_parts(if regex { ends_regex } else { ends_word })?;
+        self.regex_next = matches!(&parts[..], [WordPart::Literal(op)] if op == "=~");
+
+        if !parts.is_empty() {
+            Ok(Token::Word(parts))
+        } else {
+            Err(ParseError::NotFound)
+        }
+    }
+
+    /// Lexes the pieces of a word up to the first unquoted character where `ends_word` is
+    /// true of the rest of the input.
+    fn lex_word_parts(&mut self, ends_word: fn(&str) -> bool) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+        // What's been read since the quote we're currently inside of was opened
+        let mut quoted = Vec::new();
         let mut in_single_quotes = false;
         let mut in_double_quotes = false;
+        // Where the quote we're currently inside of was opened
+        let mut quote_start = 0;
+        // A bad expansion is only reported once the whole word is read, so the
+        // quotes around it still get matched up
+        let mut error = None;
 
-        while let Some(&c) = self.chars.peek() {
+        while let Some(c) = self.peek() {
             if in_single_quotes {
-                self.chars.next();
+                self.bump();
                 if c == '\'' {
                     in_single_quotes = false;
+                    push_part(&mut parts, WordPart::Quoted(std::mem::take(&mut quoted)));
                 } else {
-                    word.push(c);
+                    push_char(&mut quoted, c);
                 }
             } else if in_double_quotes {
-                self.chars.next();
-                if c == '"' {
-                    in_double_quotes = false;
-                } else {
-                    word.push(c);
+                if c == '$' {
+                    match self.lex_expansion() {
+                        Ok(part) => push_part(&mut quoted, part),
+                        Err(e) => error = error.or(Some(e)),
+                    }
+                    continue;
                 }
-            } else if c.is_whitespace() || c == '|' {
+
+                self.bump();
+                match c {
+                    '"' => {
+                        in_double_quotes = false;
+                        push_part(&mut parts, WordPart::Quoted(std::mem::take(&mut quoted)));
+                    }
+                    // Only a few characters can be escaped inside double quotes; before
+                    // anything else the backslash is kept
+                    '\\' => match self.peek() {
+                        // Line continuation
+                        Some('\n') => {
+                            self.bump();
+                        }
+                        Some(escaped @ ('$' | '`' | '"' | '\\')) => {
+                            self.bump();
+                            push_char(&mut quoted, escaped);
+                        }
+                        _ => push_char(&mut quoted, c),
+                    },
+                    _ => push_char(&mut quoted, c),
+                }
+            } else if matches!(c, '<' | '>') && self.rest()[1..].starts_with('(') {
+                let start = self.pos;
+                self.bump();
+                // The command starts after the '('
+                let offset = self.offset + self.pos + 1;
+                let command = self.subshell_inner(start)?;
+                push_part(&mut parts, WordPart::ProcSub { command, offset, output: c == '>' });
+            } else if ends_word(self.rest()) {
                 break;
+            } else if c == '\\' {
+                if matches!(self.rest(), "\\" | "\\\n") {
+                    // A backslash at the very end is asking for another line
+                    let span = self.char_span(self.pos);
+                    self.pos = self.input.len();
+                    return Err(ParseError::UnexpectedEof(span));
+                }
+                self.bump();
+                match self.bump() {
+                    // Line continuation, so the word carries on from the next line
+                    Some('\n') | None => {}
+                    // Anything else is taken literally, even quotes and operators
+                    Some(escaped) => {
+                        push_part(&mut parts, WordPart::Quoted(vec![WordPart::Literal(escaped.to_string())]));
+                    }
+                }
             } else if c == '\'' {
-                self.chars.next();
+                quote_start = self.pos;
+                self.bump();
                 in_single_quotes = true;
             } else if c == '"' {
-                self.chars.next();
+                quote_start = self.pos;
+                self.bump();
                 in_double_quotes = true;
-            } else if c == ';' || c == '>' || c == '&' {
-                break;
-            } else if c.is_ascii_digit()
-                && self
-                    .chars
-                    .clone()
-                    .next_if(|&next_c| next_c == '>')
-                    .is_some()
-            {
-                // Only break on digits if they're followed by '>', like "2>"
-                break;
+            } else if self.rest().starts_with("$'") {
+                let text = self.lex_ansi_c_quote()?;
+                push_part(&mut parts, WordPart::Quoted(vec![WordPart::Literal(text)]));
+            } else if self.rest().starts_with("$\"") {
+                // A string to translate for the current locale, which we don't do, so
+                // it's just double quoted
+                self.bump();
+            } else if c == '$' {
+                match self.lex_expansion() {
+                    Ok(part) => push_part(&mut parts, part),
+                    Err(e) => error = error.or(Some(e)),
+                }
             } else {
-                self.chars.next();
-                word.push(c);
+                self.bump();
+                push_char(&mut parts, c);
             }
         }
 
+        if let Some(e) = error {
+            return Err(e);
+        }
         if in_single_quotes || in_double_quotes {
-            return Err(ParseError::UnterminatedStringLiteral);
+            return Err(ParseError::UnterminatedStringLiteral(self.char_span(quote_start)));
         }
 
-        if !word.is_empty() {
-            Ok(Token::Word(word))
-        } else {
-            Err(ParseError::NotFound)
+        Ok(parts)
+    }
+
+    /// Lexes a `$'...'` string, decoding the C-style escapes in it.
+    fn lex_ansi_c_quote(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        self.eat("$'");
+
+        let mut text = String::new();
+        // Like in C, a NUL ends the string, but we still need to find the closing quote
+        let mut ended = false;
+
+        loop {
+            let escape_start = self.pos;
+            let c = match self.bump() {
+                Some('\'') => return Ok(text),
+                Some('\\') => match self.bump() {
+                    Some(escaped) => match self.ansi_c_escape(escaped) {
+                        Some(c) => c,
+                        None => {
+                            // Not an escape we know, so it's kept as written
+                            if !ended {
+                                text.push_str(&self.input[escape_start..self.pos]);
+                            }
+                            continue;
+                        }
+                    },
+                    None => break,
+                },
+                Some(c) => c,
+                None => break,
+            };
+
+            if c == '\0' {
+                ended = true;
+            } else if !ended {
+                text.push(c);
+            }
         }
+
+        Err(ParseError::UnterminatedStringLiteral(self.char_span(start)))
     }
 
-    fn lex_and_then(&mut self) -> Option<Token> {
-        let mut iter = self.chars.clone();
-
-        if let Some(&c) = iter.peek() {
-            if c == '&' {
-                iter.next();
-
-                if let Some(&next_c) = iter.peek() {
-                    if next_c == '&' {
-                        self.chars.next();
-                        self.chars.next();
-                        Some(Token::AndThenIf)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else if c == ';' {
-                self.chars.next();
-                return Some(Token::AndThen);
-            } else {
-                return None;
+    /// Decodes the escape sequence `\escaped...` in a `$'...'` string, consuming any digits after
+    /// `escaped`, or `None` if it's not a valid escape.
+    fn ansi_c_escape(&mut self, escaped: char) -> Option<char> {
+        let c = match escaped {
+            'a' => '\x07',
+            'b' => '\x08',
+            'e' | 'E' => '\x1b',
+            'f' => '\x0c',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'v' => '\x0b',
+            '\\' | '\'' | '"' | '?' => escaped,
+            'c' => {
+                let control = self.peek().filter(char::is_ascii)?;
+                self.bump();
+                char::from(control as u8 & 0x1f)
+            }
+            'x' => char::from_u32(self.char_code(16, 2)?)?,
+            'u' => char::from_u32(self.char_code(16, 4)?)?,
+            'U' => char::from_u32(self.char_code(16, 8)?)?,
+            // `\0NNN`, with up to three octal digits after the zero
+            '0' => char::from_u32(self.char_code(8, 3).unwrap_or(0))?,
+            // `\NNN`, with up to three octal digits in all
+            '1'..='7' => {
+                self.pos -= 1;
+                char::from_u32(self.char_code(8, 3)?)?
             }
+            _ => return None,
+        };
+        Some(c)
+    }
+
+    /// Consumes up to `max_digits` digits in base `radix`, returning their value if there were any.
+    fn char_code(&mut self, radix: u32, max_digits: usize) -> Option<u32> {
+        let digits = self
+            .rest()
+            .chars()
+            .take(max_digits)
+            .take_while(|c| c.is_digit(radix))
+            .count();
+        if digits == 0 {
+            return None;
+        }
+
+        let code = u32::from_str_radix(&self.rest()[..digits], radix).ok()?;
+        self.pos += digits;
+        Some(code)
+    }
+
+    fn lex_and_then(&mut self) -> Option<Token> {
+        if self.eat("&&") {
+            Some(Token::AndThenIf)
+        } else if self.eat(";;&") {
+            Some(Token::CaseEnd(CaseTerminator::Continue))
+        } else if self.eat(";;") {
+            Some(Token::CaseEnd(CaseTerminator::Break))
+        } else if self.eat(";&") {
+            Some(Token::CaseEnd(CaseTerminator::FallThrough))
+        } else if self.eat(";") {
+            Some(Token::AndThen)
         } else {
             None
         }
     }
 
     fn lex_redirection(&mut self) -> Option<Token> {
-        let mut iter = self.chars.clone();
-        let mut redir = String::new();
+        let rest = self.rest();
+        let (token, fd_len) = match rest.chars().next()? {
+            '1' => (Token::RedirOut, 1),
+            '2' => (Token::RedirErr, 1),
+            '&' => (Token::RedirBoth, 1),
+            _ => (Token::RedirOut, 0),
+        };
+
+        // `>(` is a process substitution instead
+        if !rest[fd_len..].starts_with('>') || (fd_len == 0 && rest.starts_with(">(")) {
+            return None;
+        }
 
-        if let Some(&c) = iter.peek() {
-            if c == '1' || c == '2' || c == '&' {
-                redir.push(c);
-                iter.next();
-            }
+        // Skip the fd, the '>', and the second '>' of a ">>" if there is one
+        self.pos += fd_len;
+        self.eat(">");
+        self.eat(">");
+        Some(token)
+    }
+
+    /// Lexes a `<<< word`, whose expansion (plus a newline) is fed to the command's stdin.
+    fn lex_here_string(&mut self) -> Result<Token, ParseError> {
+        let start = self.pos;
+        if !self.eat("<<<") {
+            return Err(ParseError::NotFound);
         }
 
-        if let Some(&c) = iter.peek() {
-            if c == '>' {
-                redir.push(c);
-                iter.next();
+        self.skip_whitespace();
+        let parts = self.lex_word_parts(ends_word)?;
+        if parts.is_empty() {
+            return Err(ParseError::UnexpectedToken(self.span_from(start)));
+        }
+        Ok(Token::HereString(parts))
+    }
 
-                if let Some(&next_c) = iter.peek() {
-                    if next_c == '>' {
-                        redir.push(next_c);
-                        iter.next();
-                    }
-                }
-            } else {
-                return None;
-            }
-        } else {
-            return None;
+    /// Lexes a `<<DELIMITER` (or `<<-DELIMITER`, which strips leading tabs), reading the
+    /// here-document's body from the lines after this one up to the line that's just `DELIMITER`.
+    ///
+    /// Unless some of the delimiter is quoted, the body has expansions in it, like a double-quoted string.
+    fn lex_heredoc(&mut self) -> Result<Token, ParseError> {
+        let start = self.pos;
+        if !self.eat("<<") {
+            return Err(ParseError::NotFound);
         }
+        let strip_tabs = self.eat("-");
 
-        match redir.as_str() {
-            ">" | "1>" | ">>" | "1>>" => {
-                for _ in 0..redir.len() {
-                    self.chars.next();
-                }
-                Some(Token::RedirOut)
+        self.skip_whitespace();
+        let delimiter_start = self.pos;
+        self.lex_word_parts(ends_word)?;
+        let raw_delimiter = &self.input[delimiter_start..self.pos];
+        if raw_delimiter.is_empty() {
+            return Err(ParseError::UnexpectedToken(self.span_from(start)));
+        }
+        let quoted = raw_delimiter.contains(['\'', '"', '\\']);
+        let delimiter = raw_delimiter.replace(['\'', '"', '\\'], "");
+
+        // The body starts on the next line, or after the bodies of the here-documents before this one
+        let mut line_start = match self.heredoc_end {
+            Some(end) => end,
+            None => self.next_line_start(),
+        };
+        let mut body = String::new();
+        loop {
+            if line_start >= self.input.len() {
+                return Err(ParseError::UnterminatedHereDoc(self.span_from(start)));
             }
-            "2>" | "2>>" => {
-                for _ in 0..redir.len() {
-                    self.chars.next();
-                }
-                Some(Token::RedirErr)
+            let rest = &self.input[line_start..];
+            let line_len = rest.find('\n').map_or(rest.len(), |newline| newline + 1);
+            let mut line = rest[..line_len].strip_suffix('\n').unwrap_or(&rest[..line_len]);
+            if strip_tabs {
+                line = line.trim_start_matches('\t');
             }
-            "&>" | "&>>" => {
-                for _ in 0..redir.len() {
-                    self.chars.next();
-                }
-                Some(Token::RedirBoth)
+            line_start += line_len;
+
+            if line == delimiter {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        self.heredoc_end = Some(line_start);
+
+        if quoted {
+            return Ok(Token::HereDoc(vec![WordPart::Quoted(vec![WordPart::Literal(body)])]));
+        }
+        let mut body_lexer = Lexer::new_at(&body, self.offset + line_start);
+        Ok(Token::HereDoc(vec![WordPart::Quoted(body_lexer.lex_heredoc_body()?)]))
+    }
+
+    /// Where the line after this one starts, skipping over anything (like a quoted string)
+    /// that carries on across lines.
+    fn next_line_start(&self) -> usize {
+        let mut lexer = self.clone();
+        loop {
+            match lexer.next() {
+                Some(Ok((Token::Newline, span))) => return span.end - self.offset,
+                Some(_) => {}
+                None => return self.input.len(),
             }
-            _ => None,
         }
     }
 
-    fn lex_pipe(&mut self) -> Option<Token> {
-        let mut iter = self.chars.clone();
-
-        if let Some(c) = iter.next() {
-            if c == '|' {
-                if let Some(&next_c) = iter.peek() {
-                    if next_c == '&' {
-                        self.chars.next();
-                        self.chars.next();
-                        return Some(Token::PipeBoth);
+    /// Lexes the body of a here-document, where there are expansions but no quotes, and
+    /// backslashes only escape `$`, `` ` ``, `\` and newlines.
+    fn lex_heredoc_body(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                '$' => push_part(&mut parts, self.lex_expansion()?),
+                '\\' => {
+                    self.bump();
+                    match self.peek() {
+                        Some(escaped @ ('$' | '`' | '\\')) => {
+                            self.bump();
+                            push_char(&mut parts, escaped);
+                        }
+                        Some('\n') => {
+                            self.bump();
+                        }
+                        _ => push_char(&mut parts, '\\'),
                     }
                 }
-                self.chars.next();
-                return Some(Token::Pipe);
+                c => {
+                    self.bump();
+                    push_char(&mut parts, c);
+                }
             }
         }
-        None
+
+        Ok(parts)
     }
 
-    // add a custom error type maybe
-    fn subshell_inner(&mut self) -> Result<String, ParseError> {
-        let mut inner_string = String::new();
+    fn lex_pipe(&mut self) -> Option<Token> {
+        if self.eat("|&") {
+            Some(Token::PipeBoth)
+        } else if self.eat("|") {
+            Some(Token::Pipe)
+        } else {
+            None
+        }
+    }
+
+    /// Lexes from an opening '(' through its matching ')', returning what's between them.
+    ///
+    /// `start` is where the construct began (e.g. the '$' of "$("), for error reporting.
+    fn subshell_inner(&mut self, start: usize) -> Result<String, ParseError> {
         let mut open_parens = 1;
-        let mut matched = false;
 
         // We don't want that first '('
-        self.chars.next();
+        self.bump();
+        let inner_start = self.pos;
 
-        while let Some(&c) = self.chars.peek() {
+        while let Some(c) = self.bump() {
             match c {
+                // An escaped paren doesn't count
+                '\\' => {
+                    self.bump();
+                }
+                '(' => open_parens += 1,
                 ')' => {
+                    open_parens -= 1;
                     if open_parens == 0 {
-                        break;
-                    } else if open_parens == 1 {
-                        self.chars.next();
-                        matched = true;
-                        break;
-                    } else {
-                        open_parens -= 1;
+                        return Ok(self.input[inner_start..self.pos - 1].to_string());
                     }
                 }
-                '(' => {
-                    open_parens += 1;
-                }
                 _ => {}
             }
-            self.chars.next();
-            inner_string.push(c);
-        }
-        if matched {
-            Ok(inner_string)
-        } else {
-            Err(ParseError::UnmatchedDelimiterError)
         }
+
+        Err(ParseError::UnmatchedDelimiterError(
+            Span::new(self.offset + start, self.offset + inner_start),
+        ))
     }
 
-    fn lex_subshell(&mut self) -> Result<Token, ParseError> {
-        let mut iter = self.chars.clone();
-
-        if let Some(c) = iter.next() {
-            if c == '$' {
-                if let Some(&next_c) = iter.peek() {
-                    if next_c == '(' {
-                        self.chars.next();
-                        let inner_string = self.subshell_inner()?;
-                        return Ok(Token::SubShell(inner_string));
+    /// Lexes the expression in `((...))`, where `start` is where the construct began.
+    ///
+    /// Returns `None` (having consumed nothing) if the parentheses turn out not to be
+    /// an arithmetic expression after all, like in `((cd dir) && ls)`.
+    fn lex_arith(&mut self, start: usize) -> Result<Option<Vec<WordPart>>, ParseError> {
+        let open = self.pos;
+        self.eat("((");
+        let mut parts = Vec::new();
+        let mut open_parens = 0;
+
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(ParseError::UnmatchedDelimiterError(
+                        Span::new(self.offset + start, self.offset + open + 2),
+                    ));
+                }
+                Some('$') => {
+                    let part = self.lex_expansion()?;
+                    push_part(&mut parts, part);
+                    continue;
+                }
+                Some('(') => open_parens += 1,
+                Some(')') if open_parens > 0 => open_parens -= 1,
+                Some(')') => {
+                    if self.eat("))") {
+                        return Ok(Some(parts));
                     }
+                    self.pos = open;
+                    return Ok(None);
                 }
-            } else if c == '(' {
-                let inner_string = self.subshell_inner()?;
-                return Ok(Token::SubShell(inner_string));
+                Some(_) => {}
+            }
+
+            if let Some(c) = self.bump() {
+                push_char(&mut parts, c);
             }
         }
-        Err(ParseError::NotFound)
+    }
+
+    fn lex_subshell(&mut self) -> Result<Token, ParseError> {
+        if !self.rest().starts_with('(') {
+            return Err(ParseError::NotFound);
+        }
+
+        if self.rest().starts_with("((") {
+            if let Some(parts) = self.lex_arith(self.pos)? {
+                return Ok(Token::Arith(parts));
+            }
+        }
+
+        let inner_string = self.subshell_inner(self.pos)?;
+        Ok(Token::SubShell(inner_string))
     }
 
     /*
@@ -254,67 +649,219 @@ impl<'a> Lexer<'a> {
      *         referred to as an identifier
      */
 
-    fn lex_variable(&mut self) -> Result<Token, ParseError> {
-        if let Some(&c) = self.chars.peek() {
-            if c == '$' {
-                self.chars.next();
-
-                if !self
-                    .chars
-                    .peek()
-                    .is_some_and(|&ch| ch.is_alphabetic() || ch == '_')
-                {
-                    return Err(ParseError::InvalidVariable);
+    /// Lexes the name of a parameter: a variable's name, a positional parameter's number,
+    /// or one of the special parameters like `$?`, returning "" if there isn't one.
+    ///
+    /// Without braces (`$10` rather than `${10}`) only the first digit of a number counts.
+    fn lex_param_name(&mut self, braced: bool) -> &'a str {
+        let start = self.pos;
+        match self.peek() {
+            Some(ch) if ch.is_alphabetic() || ch == '_' => {
+                while self.peek().is_some_and(|ch| ch.is_alphanumeric() || ch == '_') {
+                    self.bump();
                 }
+            }
+            Some(ch) if ch.is_ascii_digit() => {
+                self.bump();
+                while braced && self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+                    self.bump();
+                }
+            }
+            Some(ch) if is_special_param(ch) => {
+                self.bump();
+            }
+            _ => {}
+        }
+        &self.input[start..self.pos]
+    }
 
-                let variable_name: String = self
-                    .chars
-                    .clone()
-                    .take_while(|&ch| ch.is_alphanumeric() || ch == '_')
-                    .collect();
+    /// Lexes a `$NAME`, `${...}` or `$(command)` found somewhere in a word.
+    fn lex_expansion(&mut self) -> Result<WordPart, ParseError> {
+        let start = self.pos;
+        self.eat("$");
 
-                for _ in 0..variable_name.len() {
-                    self.chars.next();
-                }
-                return Ok(Token::Variable(variable_name));
+        if self.rest().starts_with("((") {
+            if let Some(parts) = self.lex_arith(start)? {
+                return Ok(WordPart::Arith(parts));
+            }
+        }
+
+        if self.rest().starts_with('(') {
+            let command = self.subshell_inner(start)?;
+            // The command ends just before the closing ')'
+            let offset = self.offset + self.pos - 1 - command.len();
+            return Ok(WordPart::SubShell { command, offset });
+        }
+
+        if self.rest().starts_with('{') {
+            return self.lex_braced_param(start).map(WordPart::Variable);
+        }
+
+        let name = self.lex_param_name(false);
+        if name.is_empty() {
+            // A '$' that isn't the start of anything is just a '$'
+            return Ok(WordPart::Literal("$".to_string()));
+        }
+
+        Ok(WordPart::Variable(name.into()))
+    }
+
+    /// Lexes a `${...}` parameter expansion, where `start` is the '$'.
+    fn lex_braced_param(&mut self, start: usize) -> Result<Param<Vec<WordPart>>, ParseError> {
+        // Everything up to the first unquoted '}' is part of the operand word
+        fn ends_operand(rest: &str) -> bool {
+            rest.starts_with('}')
+        }
+
+        self.eat("{");
+        let open_end = self.pos;
+        // `${#NAME}` is NAME's length, but `${#}` is the number of positional parameters
+        let length = self.rest().starts_with('#')
+            && self.rest()[1..]
+                .starts_with(|ch: char| ch.is_alphanumeric() || ch == '_' || is_special_param(ch));
+        if length {
+            self.bump();
+        }
+        let mut name = self.lex_param_name(true).to_string();
+        // `${NAME[subscript]}` is an element of an array, which stays part of the name
+        if is_name(&name) && self.rest().starts_with('[') {
+            let Some(end) = self.rest().find(']') else {
+                return Err(self.bad_substitution(start, open_end));
+            };
+            name.push_str(&self.rest()[..=end]);
+            self.pos += end + 1;
+        }
+
+        let op = match self.peek() {
+            _ if name.is_empty() => return Err(self.bad_substitution(start, open_end)),
+            _ if length => Some(ParamOp::Length),
+            Some('}') => None,
+            Some(':' | '-' | '=' | '?' | '+') => {
+                let colon = self.eat(":");
+                let action = match self.peek() {
+                    Some('-') => MissingAction::Default,
+                    Some('=') => MissingAction::Assign,
+                    Some('?') => MissingAction::Error,
+                    Some('+') => MissingAction::Alternative,
+                    _ => return Err(self.bad_substitution(start, open_end)),
+                };
+                self.bump();
+                let word = self.lex_word_parts(ends_operand)?;
+                Some(ParamOp::Missing { action, colon, word })
+            }
+            Some('#') => {
+                self.bump();
+                let longest = self.eat("#");
+                let pattern = self.lex_word_parts(ends_operand)?;
+                Some(ParamOp::TrimPrefix { pattern, longest })
+            }
+            Some('%') => {
+                self.bump();
+                let longest = self.eat("%");
+                let pattern = self.lex_word_parts(ends_operand)?;
+                Some(ParamOp::TrimSuffix { pattern, longest })
+            }
+            Some('/') => {
+                self.bump();
+                let mode = if self.eat("/") {
+                    ReplaceMode::All
+                } else if self.eat("#") {
+                    ReplaceMode::Prefix
+                } else if self.eat("%") {
+                    ReplaceMode::Suffix
+                } else {
+                    ReplaceMode::First
+                };
+                let pattern = self.lex_word_parts(|rest| rest.starts_with(['/', '}']))?;
+                let replacement = if self.eat("/") {
+                    self.lex_word_parts(ends_operand)?
+                } else {
+                    Vec::new()
+                };
+                Some(ParamOp::Replace { pattern, replacement, mode })
+            }
+            _ => return Err(self.bad_substitution(start, open_end)),
+        };
+
+        if !self.eat("}") {
+            return Err(self.bad_substitution(start, open_end));
+        }
+        Ok(Param { name, op })
+    }
+
+    /// Skips the rest of a `${...}` that we can't make sense of, returning the error to give for it.
+    fn bad_substitution(&mut self, start: usize, open_end: usize) -> ParseError {
+        while let Some(c) = self.bump() {
+            if c == '}' {
+                return ParseError::BadSubstitution(self.span_from(start));
             }
         }
-        Err(ParseError::NotFound)
+        ParseError::UnmatchedBrace(Span::new(self.offset + start, self.offset + open_end))
     }
 }
 
 impl Iterator for Lexer<'_> {
-    type Item = Result<Token, ParseError>;
+    type Item = Result<(Token, Span), ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.skip_whitespace();
+        self.skip_comment();
+        let start = self.pos;
+        let regex = mem::take(&mut self.regex_next);
+
+        if self.eat("\n") {
+            let span = self.span_from(start);
+            if let Some(end) = self.heredoc_end.take() {
+                self.pos = end;
+            }
+            return Some(Ok((Token::Newline, span)));
+        }
+
+        // Nothing in a regular expression is an operator, not even a leading '('
+        if regex {
+            return match self.lex_word(true) {
+                Ok(token) => Some(Ok((token, self.span_from(start)))),
+                Err(ParseError::NotFound) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+
+        match self.lex_here_string() {
+            Ok(token) => return Some(Ok((token, self.span_from(start)))),
+            Err(ParseError::NotFound) => (),
+            Err(e) => return Some(Err(e)),
+        }
+
+        match self.lex_heredoc() {
+            Ok(token) => return Some(Ok((token, self.span_from(start)))),
+            Err(ParseError::NotFound) => (),
+            Err(e) => return Some(Err(e)),
+        }
 
         if let Some(token) = self.lex_redirection() {
-            return Some(Ok(token));
+            return Some(Ok((token, self.span_from(start))));
         }
 
         if let Some(token) = self.lex_pipe() {
-            return Some(Ok(token));
+            return Some(Ok((token, self.span_from(start))));
         }
 
         if let Some(token) = self.lex_and_then() {
-            return Some(Ok(token));
+            return Some(Ok((token, self.span_from(start))));
         }
 
         match self.lex_subshell() {
-            Ok(token) => return Some(Ok(token)),
+            Ok(token) => return Some(Ok((token, self.span_from(start)))),
             Err(ParseError::NotFound) => (),
             Err(e) => return Some(Err(e)),
         }
 
-        match self.lex_variable() {
-            Ok(token) => return Some(Ok(token)),
-            Err(ParseError::NotFound) => (),
-            Err(e) => return Some(Err(e)),
+        if self.eat(")") {
+            return Some(Ok((Token::CloseParen, self.span_from(start))));
         }
 
-        match self.lex_word() {
-            Ok(token) => Some(Ok(token)),
+        match self.lex_word(false) {
+            Ok(token) => Some(Ok((token, self.span_from(start)))),
             Err(ParseError::NotFound) => None,
             Err(e) => Some(Err(e)),
         }
@@ -1,115 +1,152 @@
+mod arith;
+mod brace;
+mod cond;
+mod expand;
+mod glob;
 mod lexer;
 mod parser;
 mod safe_wrappers;
-
-use safe_wrappers::{fork, exec, wait, ForkReturn, WaitReturn, pipe, close, dup2};
-use parser::RedirType;
+mod shell;
 
 #[cfg(test)]
 mod tests;
 
-use std::{io::{self, Write}, os::fd::{AsRawFd, RawFd}};
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    os::fd::AsRawFd,
+    process::exit,
+};
 
-use parser::{Arg, Command};
+use parser::Command;
+use safe_wrappers::read_line;
+use shell::{Jump, Shell};
 
-fn main() {
-    // Input REPL
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-    loop {
-        print!("> ");
-        stdout.flush().unwrap();
+const PS1: &str = "> ";
+/// Shown instead of `PS1` when what's been typed so far isn't a complete command
+const PS2: &str = "... ";
 
-        let mut input = String::new();
-        stdin.read_line(&mut input).unwrap();
-        let input = input.trim();
+const USAGE: &str = "usage: sig-systems-shell [-c command [name [arg ...]] | script [arg ...]]";
 
-        if input == "exit" {
-            break;
+fn main() {
+    let mut args = std::env::args();
+    let argv0 = args.next().unwrap_or_else(|| "sig-systems-shell".to_string());
+    let args = args.collect::<Vec<_>>();
+
+    let status = match args.first().map(String::as_str) {
+        Some("-c") => {
+            let Some(command) = args.get(1) else {
+                eprintln!("{argv0}: -c: option requires an argument\n{USAGE}");
+                exit(2);
+            };
+            // Like other shells, the word after the command string is `$0`
+            let name = args.get(2).cloned().unwrap_or(argv0);
+            let positional = args.get(3..).unwrap_or_default().to_vec();
+            Shell::new(name, positional).run_script(command)
+        }
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            0
         }
+        Some(path) => match fs::read_to_string(path) {
+            Ok(script) => Shell::new(path.to_string(), args[1..].to_vec()).run_script(&script),
+            Err(e) => {
+                eprintln!("{argv0}: {path}: {e}");
+                127
+            }
+        },
+        None if io::stdin().is_terminal() => repl(&mut Shell::new(argv0, Vec::new())),
+        None => run_stdin(&mut Shell::new(argv0, Vec::new())),
+    };
 
-        let command = Command::parse(input).unwrap();
-        run_commands(&command).expect("run_commands shouldn't return error");
-    }
+    exit(status);
 }
 
-fn run_command(cmd: &Command, read_from: Option<RawFd>) -> io::Result<()> {
-    use ForkReturn as FR;
-    use RedirType as RT;
-
-    if let Some(pipe_to) = cmd.pipe_to.as_ref() {
-        let pipe = pipe()?;
-
-        match fork()? {
-            FR::Parent(_) => {
-                close(pipe.read_fd)?;
-
-                let args = cmd.argv.iter().filter_map(|arg| {
-                    if let Arg::Word(w) = arg { Some(w) } else { None }
-                }).collect::<Vec<_>>();
-
-                if args.is_empty() {
-                    return Ok(())
-                }
-
-                if let Some(read_from) = read_from {
-                    dup2(read_from, libc::STDIN_FILENO)?;
-                    close(read_from)?;
-                }
-
-                match pipe_to.pipe_type {
-                    RT::Stdout => dup2(pipe.write_fd, libc::STDOUT_FILENO)?,
-                    RT::Stderr => dup2(pipe.write_fd, libc::STDERR_FILENO)?,
-                    RT::Both => {
-                        dup2(pipe.write_fd, libc::STDOUT_FILENO)?;
-                        dup2(pipe.write_fd, libc::STDERR_FILENO)?;
-                    },
-                }
-                close(pipe.write_fd)?;
+/// Reads and runs commands typed at the terminal, returning the status to exit with.
+fn repl(shell: &mut Shell) -> i32 {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    // Everything typed since the last complete command
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { PS1 } else { PS2 });
+        stdout.flush().unwrap();
 
-                if let Err(e) = exec(args[0], args.as_slice()) {
-                    eprintln!("Error running {}: {e}", args[0]);
-                    Err(io::Error::last_os_error())
-                } else {
-                    unsafe { std::hint::unreachable_unchecked() };
-                }
+        if stdin.read_line(&mut input).unwrap() == 0 {
+            // EOF, e.g. Ctrl-D
+            if input.trim().is_empty() {
+                return shell.last_status;
             }
-            FR::Child => {
-                close(pipe.write_fd)?;
-                run_command(pipe_to.target.as_ref(), Some(pipe.read_fd))
-            },
-        }
-    } else {
-        let args = cmd.argv.iter().filter_map(|arg| {
-            if let Arg::Word(w) = arg { Some(w) } else { None }
-        }).collect::<Vec<_>>();
 
-        if args.is_empty() {
-            return Ok(())
-        }
-
-        if let Some(read_from) = read_from {
-            dup2(read_from, libc::STDIN_FILENO)?;
-            close(read_from)?;
+            // Whatever was being typed never got finished
+            println!();
+            if let Err(errors) = Command::parse(&input) {
+                eprintln!("{errors}");
+            }
+            return 2;
         }
 
-        if let Err(e) = exec(args[0], args.as_slice()) {
-            eprintln!("Error running {}: {e}", args[0]);
-            Err(io::Error::last_os_error())
-        } else {
-            unsafe { std::hint::unreachable_unchecked() };
+        match Command::parse(&input) {
+            Ok(command) => {
+                input.clear();
+                match shell.run_list(&command) {
+                    Err(Jump::Exit(status)) => return status,
+                    // The error's already been reported, and the prompt carries on
+                    Err(jump) => shell.last_status = jump.status(),
+                    Ok(_) => {}
+                }
+            }
+            Err(errors) if errors.is_empty_input() => {
+                // A blank line or a comment, which is fine
+                input.clear();
+            }
+            Err(errors) if errors.is_incomplete() => {
+                // Keep reading until the command is finished
+            }
+            Err(errors) => {
+                input.clear();
+                eprintln!("{errors}");
+                shell.last_status = 2;
+            }
         }
     }
 }
 
-fn run_commands(cmd: &Command) -> io::Result<()> {
-    match fork()? {
-        ForkReturn::Parent(_) => {
-            wait()?;
-            Ok(())
+/// Runs commands piped in on stdin, returning the status to exit with.
+///
+/// Like at the terminal, each command runs as soon as it's been read in full, so
+/// the commands can read the rest of stdin themselves, and stdin doesn't need to end.
+fn run_stdin(shell: &mut Shell) -> i32 {
+    let fd = io::stdin().as_raw_fd();
+    let mut bytes = Vec::new();
+    loop {
+        let read = match read_line(fd, &mut bytes) {
+            Ok(read) => read,
+            Err(e) => {
+                eprintln!("{}: reading standard input: {e}", shell.name);
+                return 1;
+            }
+        };
+        let input = String::from_utf8_lossy(&bytes).into_owned();
+
+        match Command::parse(&input) {
+            Ok(command) => {
+                bytes.clear();
+                if let Err(jump) = shell.run_list(&command) {
+                    return jump.status();
+                }
+            }
+            Err(errors) if errors.is_empty_input() => bytes.clear(),
+            // Keep reading until the command is finished, unless there's nothing left
+            Err(errors) if errors.is_incomplete() && read > 0 => {}
+            Err(errors) => {
+                eprintln!("{}: {}", shell.name, errors.with_source(&input));
+                return 2;
+            }
         }
-        ForkReturn::Child => {
-            run_command(cmd, None)
+
+        if read == 0 {
+            return shell.last_status;
         }
     }
 }
@@ -1,24 +1,170 @@
-use std::path::PathBuf;
-use std::{hint::unreachable_unchecked, iter::Peekable};
+use std::fmt;
+use std::iter::Peekable;
+use std::mem;
+use std::rc::Rc;
 
-use crate::lexer::{Lexer, Token};
+use crate::brace::expand_braces;
+use crate::cond;
+use crate::lexer::{Lexer, Span, Token, WordPart};
 
 #[derive(Debug)]
 pub enum ParseError {
     Empty,
-    MissingFileName,
-    UnmatchedDelimiterError,
-    InvalidVariable,
-    UnterminatedStringLiteral,
-    NonRedirTypeToken,
+    MissingFileName(Span),
+    UnmatchedDelimiterError(Span),
+    /// A `${...}` that doesn't make sense, like `${}` or `${VAR!}`
+    BadSubstitution(Span),
+    UnmatchedBrace(Span),
+    UnterminatedStringLiteral(Span),
+    /// A here-document whose delimiter never showed up
+    UnterminatedHereDoc(Span),
+    MissingCommand(Span),
+    /// Something that can't go where it is, like a word after `(( ))`
+    UnexpectedToken(Span),
+    /// The input ended partway through something, which more input could finish
+    UnexpectedEof(Span),
     NotFound,
 }
 
+impl ParseError {
+    /// Where in the input the error is, if it's anywhere in particular.
+    pub fn span(&self) -> Option<Span> {
+        use ParseError as PE;
+
+        match *self {
+            PE::MissingFileName(span)
+            | PE::UnmatchedDelimiterError(span)
+            | PE::BadSubstitution(span)
+            | PE::UnmatchedBrace(span)
+            | PE::UnterminatedStringLiteral(span)
+            | PE::UnterminatedHereDoc(span)
+            | PE::MissingCommand(span)
+            | PE::UnexpectedToken(span)
+            | PE::UnexpectedEof(span) => Some(span),
+            PE::Empty | PE::NotFound => None,
+        }
+    }
+
+    /// Whether this error only happened because the input ended too soon.
+    pub fn is_incomplete(&self) -> bool {
+        use ParseError as PE;
+
+        // The lexer only gives these errors when it runs out of input
+        matches!(
+            self,
+            PE::UnexpectedEof(_)
+                | PE::UnterminatedStringLiteral(_)
+                | PE::UnterminatedHereDoc(_)
+                | PE::UnmatchedDelimiterError(_)
+                | PE::UnmatchedBrace(_)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseError as PE;
+
+        match self {
+            PE::Empty => write!(f, "empty command"),
+            PE::MissingFileName(_) => write!(f, "expected a file name after redirection"),
+            PE::UnmatchedDelimiterError(_) => write!(f, "unmatched '('"),
+            PE::BadSubstitution(_) => write!(f, "bad substitution"),
+            PE::UnmatchedBrace(_) => write!(f, "unmatched '{{'"),
+            PE::UnterminatedStringLiteral(_) => write!(f, "unterminated string literal"),
+            PE::UnterminatedHereDoc(_) => write!(f, "here-document never ends"),
+            PE::MissingCommand(_) => write!(f, "expected a command"),
+            PE::UnexpectedToken(_) => write!(f, "unexpected token"),
+            PE::UnexpectedEof(_) => write!(f, "unexpected end of input"),
+            PE::NotFound => write!(f, "nothing to parse"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 #[derive(Debug)]
 pub struct ParseErrors {
     errors: Vec<ParseError>,
+    /// The input the errors' spans point into, once we know it
+    source: Option<String>,
+}
+
+impl ParseErrors {
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    /// Whether there was nothing to parse, e.g. the input was blank or just a comment.
+    pub fn is_empty_input(&self) -> bool {
+        matches!(self.errors.as_slice(), [ParseError::Empty])
+    }
+
+    /// Whether more input could fix these errors, as opposed to the input being wrong.
+    ///
+    /// That's only when every error comes from running out of input, since more input
+    /// can't fix a real syntax error earlier on.
+    pub fn is_incomplete(&self) -> bool {
+        !self.errors.is_empty() && self.errors.iter().all(ParseError::is_incomplete)
+    }
 }
 
+impl From<ParseError> for ParseErrors {
+    fn from(error: ParseError) -> Self {
+        ParseErrors { errors: vec![error], source: None }
+    }
+}
+
+/// Writes the line of `source` that `span` starts on, with a '^' under each spanned character.
+fn write_snippet(f: &mut fmt::Formatter<'_>, source: &str, span: Span) -> fmt::Result {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let end = span.end.clamp(start, line_end);
+
+    // Keep any tabs so the carets line up with the text above them
+    let padding = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(source[start..end].chars().count().max(1));
+
+    write!(f, "\n{}\n{padding}{carets}", &source[line_start..line_end])
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only bother with line numbers when there's more than one line
+        let multi_line = self
+            .source
+            .as_ref()
+            .is_some_and(|source| source.trim_end().contains('\n'));
+
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            match (&self.source, error.span()) {
+                (Some(source), Some(span)) => {
+                    if multi_line {
+                        let line = source[..span.start.min(source.len())].matches('\n').count() + 1;
+                        write!(f, "syntax error on line {line}: {error}")?;
+                    } else {
+                        write!(f, "syntax error: {error}")?;
+                    }
+                    write_snippet(f, source, span)?;
+                }
+                _ => write!(f, "syntax error: {error}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
 impl IntoIterator for ParseErrors {
     type Item = ParseError;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -37,24 +183,222 @@ impl<'a> IntoIterator for &'a ParseErrors {
     }
 }
 
+/// A word of a command, or a piece of one.
 #[derive(Debug, PartialEq)]
 pub enum Arg {
+    /// Literal text
     Word(String),
-    Variable(String),
+    Variable(Param),
     Subshell(Command),
+    /// `$(( expression ))`, where the expression is expanded before it's evaluated
+    Arith(Box<Arg>),
+    /// `<(command)`, or `>(command)` if it's `output`, which becomes the path of a pipe
+    /// to or from the command
+    ProcSub { command: Command, output: bool },
+    /// `~` followed by a user's name (or nothing, `+` or `-`), for a home or working directory
+    Tilde(String),
+    /// Pieces that were quoted, so their expansions aren't split or globbed
+    Quoted(Vec<Arg>),
+    /// Pieces written right next to each other that make up one word, like `prefix$VAR`
+    Concat(Vec<Arg>),
+}
+
+/// A parameter expansion, like `$VAR` or `${VAR:-default}`.
+///
+/// The lexer uses this too, with its own kind of word as `W`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param<W = Box<Arg>> {
+    pub name: String,
+    pub op: Option<ParamOp<W>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamOp<W> {
+    /// `${#VAR}`
+    Length,
+    /// `${VAR:-word}`, `${VAR:=word}`, `${VAR:?word}` or `${VAR:+word}`, which do something
+    /// when `VAR` is unset or empty. Without the ':', an empty `VAR` doesn't count.
+    Missing { action: MissingAction, colon: bool, word: W },
+    /// `${VAR#pattern}`, or `${VAR##pattern}` to remove the longest match
+    TrimPrefix { pattern: W, longest: bool },
+    /// `${VAR%pattern}`, or `${VAR%%pattern}` to remove the longest match
+    TrimSuffix { pattern: W, longest: bool },
+    /// `${VAR/pattern/replacement}`
+    Replace { pattern: W, replacement: W, mode: ReplaceMode },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingAction {
+    /// `-`: use the word instead
+    Default,
+    /// `=`: set the variable to the word, then use it
+    Assign,
+    /// `?`: fail with the word as the error message
+    Error,
+    /// `+`: use the word only when the variable *isn't* missing
+    Alternative,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaceMode {
+    /// `/`: the first match
+    First,
+    /// `//`: every match
+    All,
+    /// `/#`: a match at the start
+    Prefix,
+    /// `/%`: a match at the end
+    Suffix,
+}
+
+impl<W> Param<W> {
+    /// Turns the words in this expansion into another kind of word.
+    pub fn map<V>(self, f: impl FnMut(W) -> V) -> Param<V> {
+        Param {
+            name: self.name,
+            op: self.op.map(|op| op.map(f)),
+        }
+    }
+}
+
+impl<W> ParamOp<W> {
+    fn map<V>(self, mut f: impl FnMut(W) -> V) -> ParamOp<V> {
+        use ParamOp as PO;
+
+        match self {
+            PO::Length => PO::Length,
+            PO::Missing { action, colon, word } => PO::Missing { action, colon, word: f(word) },
+            PO::TrimPrefix { pattern, longest } => PO::TrimPrefix { pattern: f(pattern), longest },
+            PO::TrimSuffix { pattern, longest } => PO::TrimSuffix { pattern: f(pattern), longest },
+            PO::Replace { pattern, replacement, mode } => PO::Replace {
+                pattern: f(pattern),
+                replacement: f(replacement),
+                mode,
+            },
+        }
+    }
+}
+
+impl<W> From<&str> for Param<W> {
+    fn from(name: &str) -> Self {
+        Param {
+            name: name.to_string(),
+            op: None,
+        }
+    }
+}
+
+/// A `NAME=value` word before a command's name, or on its own.
+#[derive(Debug, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Arg,
 }
 
 #[derive(Debug)]
-pub struct Parser<I: Iterator<Item = Result<Token, ParseError>>> {
+pub struct Parser<I: Iterator<Item = Result<(Token, Span), ParseError>>> {
     tokens: Peekable<I>,
+    /// Whether we're parsing the list in a `case` arm, where `;;` ends a command rather
+    /// than being a syntax error
+    in_case_arm: bool,
 }
 
+/// A command with its own syntax, like `(( ))`, rather than a name and arguments.
 #[derive(Debug, PartialEq)]
+pub enum Compound {
+    /// `(( expression ))`
+    Arith(Box<Arg>),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`, with each
+    /// condition paired up with the list that runs if it succeeds
+    If { branches: Vec<(Command, Command)>, otherwise: Option<Box<Command>> },
+    /// `while list; do list; done`, or `until` if `until`, where the body runs for as long
+    /// as the condition succeeds (or fails)
+    While { until: bool, condition: Box<Command>, body: Box<Command> },
+    /// `for name [in words]; do list; done`, where leaving out the words means `"$@"`
+    For { name: String, words: Option<Vec<Arg>>, body: Box<Command> },
+    /// `for ((init; condition; step)); do list; done`, where an empty condition is true
+    ArithFor { init: Box<Arg>, condition: Option<Box<Arg>>, step: Box<Arg>, body: Box<Command> },
+    /// `case word in [pattern [| pattern]...) list ;;]... esac`
+    Case { word: Box<Arg>, arms: Vec<CaseArm> },
+    /// `{ list; }`, which runs the list as one command
+    Group(Box<Command>),
+    /// `name() body` or `function name body`, which defines a function rather than running it
+    Function { name: String, body: Rc<Command> },
+    /// `[[ expression ]]`
+    Conditional(Box<CondExpr>),
+}
+
+/// An expression inside `[[ ]]`.
+#[derive(Debug, PartialEq)]
+pub enum CondExpr {
+    /// A word on its own, which is true if it isn't empty
+    Word(Arg),
+    /// `op word`, like `-f path` or `-z string`
+    Unary { op: String, operand: Arg },
+    /// `word op word`, like `string == pattern` or `1 -lt 2`
+    Binary { left: Arg, op: String, right: Arg },
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+/// One `pattern | pattern) list ;;` of a `case` command.
+#[derive(Debug, PartialEq)]
+pub struct CaseArm {
+    pub patterns: Vec<Arg>,
+    /// Empty arms are allowed, and do nothing
+    pub body: Option<Command>,
+    pub terminator: CaseTerminator,
+}
+
+/// What a `case` command does after running an arm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseTerminator {
+    /// `;;`: stop there
+    Break,
+    /// `;&`: run the next arm's list too, without checking its patterns
+    FallThrough,
+    /// `;;&`: carry on checking the patterns of the arms after it
+    Continue,
+}
+
+/// The words that are special at the start of a command.
+const RESERVED_WORDS: [&str; 16] = [
+    "if", "then", "elif", "else", "fi", "while", "until", "for", "do", "done", "case", "esac", "{", "}", "function",
+    "[[",
+];
+
+/// The reserved words that end a list of commands inside a compound command.
+const LIST_ENDS: [&str; 8] = ["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+/// The reserved words that start a compound command that can be a function's body.
+const COMPOUND_STARTS: [&str; 7] = ["{", "if", "while", "until", "for", "case", "[["];
+
+/// The reserved word `token` is, if it's one of `words`.
+///
+/// Reserved words are only special unquoted, and where a command's name would go.
+fn reserved_word(token: &Token, words: &[&'static str]) -> Option<&'static str> {
+    match token {
+        Token::Word(parts) => match &parts[..] {
+            [WordPart::Literal(text)] => words.iter().find(|&&word| word == text).copied(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
 pub struct Command {
+    /// Variables to set, either for just this command or in the shell if there's no command
+    pub assignments: Vec<Assignment>,
     pub argv: Vec<Arg>,
+    /// Takes the place of `argv`, which is then empty
+    pub compound: Option<Compound>,
     pub pipe_to: Option<PipeTo>,
     pub redirect_to: Vec<FileRedir>,
     pub and_then: Option<AndThen>,
+    /// Covers this command's own words and redirections, but not what it's piped or chained to
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq)]
@@ -74,150 +418,923 @@ pub enum RedirType {
     Stdout,
     Stderr,
     Both,
-}
-
-#[derive(Debug)]
-pub struct NonRedirTypeToken {}
-impl TryFrom<Token> for RedirType {
-    type Error = ParseError;
-    fn try_from(val: Token) -> Result<Self, Self::Error> {
-        use Token as T;
-        use RedirType as R;
-
-        match val {
-            T::RedirOut | T::Pipe => Ok(R::Stdout),
-            T::RedirBoth | T::PipeBoth => Ok(R::Both),
-            T::RedirErr => Ok(R::Stderr),
-            _ => Err(ParseError::NonRedirTypeToken)
-        }
-    }
+    /// Stdin from a here-document, whose body is the target
+    HereDoc,
+    /// Stdin from a here-string, which is the target and a newline
+    HereString,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct FileRedir {
     pub redirect_type: RedirType,
-    pub target: PathBuf,
+    pub target: Arg,
 }
 
-impl<I: Iterator<Item = Result<Token, ParseError>>> Parser<I> {
+impl<I: Iterator<Item = Result<(Token, Span), ParseError>>> Parser<I> {
     pub fn new(tokens: I) -> Self {
         Parser {
             tokens: tokens.peekable(),
+            in_case_arm: false,
         }
     }
 
-    fn parse_command(&mut self) -> Result<Command, ParseErrors> {
+    pub fn parse_command(&mut self) -> Result<Command, ParseErrors> {
         let mut errors = Vec::new();
+        let mut assignments = Vec::new();
         let mut argv = Vec::new();
+        let mut compound = None;
         let mut pipe_to = None;
         let mut redirect_to = Vec::new();
         let mut and_then = None;
+        let mut span: Option<Span> = None;
 
-        while let Some(token_res) = self.tokens.next() {
-            match token_res {
-                Ok(tok) => match tok {
-                    Token::Word(word) => argv.push(Arg::Word(word)),
-                    tok if matches!(tok, Token::RedirOut | Token::RedirErr | Token::RedirBoth) => {
-                        let redir_type = tok.try_into().unwrap();
-                        if let Some(Ok(Token::Word(path))) = self.tokens.next() {
-                            redirect_to.push(FileRedir {
-                                redirect_type: redir_type,
-                                target: PathBuf::from(path),
-                            });
-                        } else {
-                            errors.push(ParseError::MissingFileName);
+        loop {
+            // In a `case` arm, this ends the command, but is left for the arm to deal with
+            let at_case_end = self.in_case_arm && matches!(self.tokens.peek(), Some(Ok((Token::CaseEnd(_), _))));
+            if at_case_end && (span.is_some() || !errors.is_empty()) {
+                break;
+            }
+
+            let Some(token_res) = self.tokens.next() else {
+                break;
+            };
+            let (tok, tok_span) = match token_res {
+                Ok(token) => token,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+
+            let is_operator = matches!(tok, Token::Pipe | Token::PipeBoth | Token::AndThen | Token::AndThenIf);
+            if is_operator {
+                if argv.is_empty() && assignments.is_empty() && compound.is_none() && errors.is_empty() {
+                    errors.push(ParseError::MissingCommand(tok_span));
+                }
+            } else if !matches!(tok, Token::Newline) {
+                // Operators and newlines aren't part of the command they follow
+                span = Some(span.map_or(tok_span, |span| span.to(tok_span)));
+            }
+
+            // Reserved words only count where the command's name would go
+            let at_start = argv.is_empty() && assignments.is_empty() && compound.is_none();
+            if let Some(word) = reserved_word(&tok, &RESERVED_WORDS).filter(|_| at_start) {
+                match self.parse_compound(word, tok_span) {
+                    Ok((parsed, end_span)) => {
+                        compound = Some(parsed);
+                        span = span.map(|span| span.to(end_span));
+                    }
+                    Err(errs) => errors.extend(errs),
+                }
+                continue;
+            }
+
+            match tok {
+                // Nothing but redirections can come after a compound command
+                Token::Word(_) | Token::SubShell(_) | Token::Arith(_) if compound.is_some() => {
+                    errors.push(ParseError::UnexpectedToken(tok_span));
+                }
+                Token::Arith(parts) if argv.is_empty() && assignments.is_empty() => {
+                    compound = Some(Compound::Arith(Box::new(parse_word(parts, &mut errors))));
+                }
+                Token::Arith(_) | Token::CaseEnd(_) | Token::CloseParen => {
+                    errors.push(ParseError::UnexpectedToken(tok_span));
+                }
+                Token::Word(parts) => match assignment_name(&parts) {
+                    // Assignments only count before the command's name
+                    Some(name) if argv.is_empty() => {
+                        let name = name.to_string();
+                        let value = parse_tilde_word(strip_prefix(parts, name.len() + 1), true, &mut errors);
+                        assignments.push(Assignment { name, value });
+                    }
+                    _ => {
+                        for parts in expand_braces(parts) {
+                            argv.push(parse_tilde_word(parts, false, &mut errors));
+                        }
+                    }
+                },
+                // `name()` starts a function definition
+                Token::SubShell(parens)
+                    if parens.trim().is_empty() && assignments.is_empty() && matches!(&argv[..], [Arg::Word(_)]) =>
+                {
+                    let Some(Arg::Word(name)) = argv.pop() else {
+                        unreachable!("the function's name was just matched");
+                    };
+                    match self.parse_function_body(tok_span) {
+                        Ok((body, end_span)) => {
+                            compound = Some(Compound::Function { name, body: Rc::new(body) });
+                            span = span.map(|span| span.to(end_span));
                         }
+                        Err(errs) => errors.extend(errs),
+                    }
+                }
+                Token::SubShell(command) => {
+                    // The command ends just before the closing ')'
+                    let offset = tok_span.end - 1 - command.len();
+                    if let Some(command) = parse_subshell(&command, offset, &mut errors) {
+                        argv.push(Arg::Subshell(command));
                     }
-                    pipe_token if matches!(pipe_token, Token::Pipe | Token::PipeBoth) => {
-                        let pipe_type: RedirType = pipe_token.try_into().unwrap();
+                }
+                Token::RedirOut | Token::RedirErr | Token::RedirBoth => {
+                    let redirect_type = match tok {
+                        Token::RedirErr => RedirType::Stderr,
+                        Token::RedirBoth => RedirType::Both,
+                        _ => RedirType::Stdout,
+                    };
 
-                        match self.parse_command() {
-                            Ok(next_command) => {
-                                pipe_to = Some(PipeTo {
-                                    pipe_type,
-                                    target: Box::new(next_command),
-                                });
-                            }
-                            Err(errs) => {
-                                errors.extend(errs);
-                            }
+                    match self.parse_redir_target(tok_span) {
+                        Ok((parts, target_span)) => {
+                            span = span.map(|span| span.to(target_span));
+                            let target = parse_tilde_word(parts, false, &mut errors);
+                            redirect_to.push(FileRedir { redirect_type, target });
                         }
-                        break;
+                        Err(e) => errors.push(e),
                     }
-                    Token::AndThen => {
-                        match self.parse_command() {
-                            Ok(next_command) => {
-                                and_then = Some(AndThen {
-                                    target: Box::new(next_command),
-                                    conditional: false,
-                                });
-                            }
-                            Err(errs) => {
-                                errors.extend(errs);
-                            }
+                }
+                Token::HereDoc(parts) => {
+                    redirect_to.push(FileRedir {
+                        redirect_type: RedirType::HereDoc,
+                        target: parse_word(parts, &mut errors),
+                    });
+                }
+                Token::HereString(parts) => {
+                    redirect_to.push(FileRedir {
+                        redirect_type: RedirType::HereString,
+                        target: parse_tilde_word(parts, false, &mut errors),
+                    });
+                }
+                Token::Pipe | Token::PipeBoth => {
+                    let pipe_type = match tok {
+                        Token::PipeBoth => RedirType::Both,
+                        _ => RedirType::Stdout,
+                    };
+
+                    match self.parse_command_after(tok_span) {
+                        Ok(next_command) => {
+                            pipe_to = Some(PipeTo {
+                                pipe_type,
+                                target: Box::new(next_command),
+                            });
                         }
-                        break;
+                        Err(errs) => errors.extend(errs),
                     }
-                    Token::AndThenIf => {
+                    break;
+                }
+                Token::AndThen => {
+                    // Unlike the other operators, a trailing ';' is fine
+                    if !self.at_line_end() && !self.at_list_end() {
                         match self.parse_command() {
                             Ok(next_command) => {
                                 and_then = Some(AndThen {
                                     target: Box::new(next_command),
-                                    conditional: true,
+                                    conditional: false,
                                 });
                             }
-                            Err(errs) => {
-                                errors.extend(errs);
-                            }
-                        }
-                        break;
-                    }
-                    Token::SubShell(command) => {
-                        match Command::parse(command) {
-                            Ok(command) => argv.push(Arg::Subshell(command)),
                             Err(errs) => errors.extend(errs),
                         }
                     }
-                    Token::Variable(s) => {
-                        argv.push(Arg::Variable(s));
+                    break;
+                }
+                Token::Newline => {
+                    if span.is_none() && errors.is_empty() {
+                        // Blank lines before a command don't end it
+                        continue;
                     }
-                    _ => {
-                        // TODO: Re-evaluate this!
-                        unsafe { unreachable_unchecked() }
+                    break;
+                }
+                Token::AndThenIf => {
+                    match self.parse_command_after(tok_span) {
+                        Ok(next_command) => {
+                            and_then = Some(AndThen {
+                                target: Box::new(next_command),
+                                conditional: true,
+                            });
+                        }
+                        Err(errs) => errors.extend(errs),
                     }
-                },
-                Err(e) => {
-                    errors.push(e);
+                    break;
                 }
             }
         }
 
-        if !errors.is_empty() || argv.is_empty() {
-            Err(ParseErrors { errors })
+        if errors.is_empty() && argv.is_empty() && assignments.is_empty() && compound.is_none() {
+            errors.push(ParseError::Empty);
+        }
+
+        if !errors.is_empty() {
+            Err(ParseErrors { errors, source: None })
         } else {
             Ok(Command {
+                assignments,
                 argv,
+                compound,
                 pipe_to,
                 and_then,
                 redirect_to,
+                span: span.unwrap_or_default(),
             })
         }
     }
 
-    fn parse_redir_type(&mut self) -> RedirType {
+    /// Parses every remaining line, chaining them together as if they were joined by ';'.
+    pub fn parse_all(&mut self) -> Result<Command, ParseErrors> {
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+
+        loop {
+            match self.parse_command() {
+                Ok(command) => commands.push(command),
+                Err(errs) => errors.extend(errs),
+            }
+
+            if self.at_end() {
+                break;
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+        Ok(chain(commands))
+    }
+
+    /// Parses commands up to one of the reserved words in `LIST_ENDS`, chaining them together
+    /// as if they were joined by ';'. `after` is what came before them, to point at if
+    /// the input runs out first.
+    fn parse_list(&mut self, after: Span) -> Result<Command, ParseErrors> {
+        let commands = self.parse_commands(after)?;
+        if commands.is_empty() {
+            // Something like a `then` straight after the `if`
+            let span = self.tokens.next().and_then(Result::ok).map_or(after, |(_, span)| span);
+            return Err(ParseError::UnexpectedToken(span).into());
+        }
+        Ok(chain(commands))
+    }
+
+    /// Like `parse_list`, but there might not be any commands, and they can end at the
+    /// end of a `case` arm too. Whatever ends them is left to be parsed next.
+    fn parse_commands(&mut self, after: Span) -> Result<Vec<Command>, ParseErrors> {
+        let mut commands = Vec::new();
+
+        loop {
+            self.skip_newlines();
+            if self.tokens.peek().is_none() {
+                return Err(ParseError::UnexpectedEof(after).into());
+            } else if self.at_list_end() {
+                return Ok(commands);
+            }
+            commands.push(self.parse_command()?);
+        }
+    }
+
+    /// Whether the next token ends a list, like `fi` or `;;`.
+    fn at_list_end(&mut self) -> bool {
+        match self.tokens.peek() {
+            Some(Ok((Token::CaseEnd(_), _))) => true,
+            Some(Ok((token, _))) => reserved_word(token, &LIST_ENDS).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Takes the next token if it's the reserved word `word`, returning where it was.
+    fn next_reserved(&mut self, word: &'static str) -> Option<Span> {
+        match self.tokens.next_if(|res| matches!(res, Ok((token, _)) if reserved_word(token, &[word]).is_some())) {
+            Some(Ok((_, span))) => Some(span),
+            _ => None,
+        }
+    }
+
+    /// Takes the next token, which should be one of the reserved words in `words`, returning
+    /// which and where it is. `after` is what came before it, to point at if the input runs out.
+    fn expect_reserved(&mut self, words: &[&'static str], after: Span) -> Result<(&'static str, Span), ParseErrors> {
         match self.tokens.next() {
-            Some(Ok(Token::RedirOut)) => RedirType::Stdout,
-            Some(Ok(Token::RedirErr)) => RedirType::Stderr,
-            Some(Ok(Token::RedirBoth)) => RedirType::Both,
-            _ => panic!("peek is Token::Redir, but matched none of Redir Variants"),
+            None => Err(ParseError::UnexpectedEof(after).into()),
+            Some(Ok((token, span))) => match reserved_word(&token, words) {
+                Some(word) => Ok((word, span)),
+                None => Err(ParseError::UnexpectedToken(span).into()),
+            },
+            Some(Err(e)) => Err(e.into()),
         }
     }
+
+    /// Parses the compound command started by the reserved word `word` at `word_span`,
+    /// returning it along with the span of the word that ends it.
+    fn parse_compound(&mut self, word: &str, word_span: Span) -> Result<(Compound, Span), ParseErrors> {
+        match word {
+            "if" => self.parse_if(word_span),
+            "while" | "until" => {
+                let condition = self.parse_list(word_span)?;
+                let (body, done_span) = self.parse_do_group(word_span)?;
+                let until = word == "until";
+                Ok((Compound::While { until, condition: Box::new(condition), body: Box::new(body) }, done_span))
+            }
+            "for" => self.parse_for(word_span),
+            "case" => self.parse_case(word_span),
+            "{" => {
+                let body = self.parse_list(word_span)?;
+                let (_, close_span) = self.expect_reserved(&["}"], word_span)?;
+                Ok((Compound::Group(Box::new(body)), close_span))
+            }
+            "function" => self.parse_function(word_span),
+            "[[" => {
+                let expr = self.parse_cond_or(word_span)?;
+                let (_, close_span) = self.expect_reserved(&["]]"], word_span)?;
+                Ok((Compound::Conditional(Box::new(expr)), close_span))
+            }
+            // Something like a `fi` with no `if`
+            _ => Err(ParseError::UnexpectedToken(word_span).into()),
+        }
+    }
+
+    /// Parses the rest of a `for` loop after the `for` at `for_span`, returning it along
+    /// with the span of its `done`.
+    fn parse_for(&mut self, for_span: Span) -> Result<(Compound, Span), ParseErrors> {
+        let (name, name_span) = match self.tokens.next() {
+            None => return Err(ParseError::UnexpectedEof(for_span).into()),
+            Some(Err(e)) => return Err(e.into()),
+            Some(Ok((Token::Arith(parts), span))) => return self.parse_arith_for(parts, span),
+            Some(Ok((Token::Word(parts), span))) => match &parts[..] {
+                [WordPart::Literal(name)] if is_name(name) => (name.clone(), span),
+                _ => return Err(ParseError::UnexpectedToken(span).into()),
+            },
+            Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+        };
+
+        // The `in` can be on a later line, but the words have to be on the same line as it
+        self.skip_newlines();
+        let (words, last_span) = match self.next_reserved("in") {
+            Some(in_span) => {
+                let (words, last_span) = self.parse_for_words(in_span)?;
+                match self.tokens.next() {
+                    None => return Err(ParseError::UnexpectedEof(last_span).into()),
+                    Some(Err(e)) => return Err(e.into()),
+                    Some(Ok((Token::AndThen | Token::Newline, span))) => (Some(words), span),
+                    Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+                }
+            }
+            _ => (None, self.skip_separator().unwrap_or(name_span)),
+        };
+
+        self.skip_newlines();
+        let (body, done_span) = self.parse_do_group(last_span)?;
+        Ok((Compound::For { name, words, body: Box::new(body) }, done_span))
+    }
+
+    /// Parses the words after a `for` loop's `in` at `in_span`, returning them along with
+    /// the span of the last one.
+    fn parse_for_words(&mut self, in_span: Span) -> Result<(Vec<Arg>, Span), ParseErrors> {
+        let mut errors = Vec::new();
+        let mut words = Vec::new();
+        let mut last_span = in_span;
+
+        while let Some(Ok((Token::Word(parts), span))) = self.tokens.next_if(|res| matches!(res, Ok((Token::Word(_), _)))) {
+            for parts in expand_braces(parts) {
+                words.push(parse_tilde_word(parts, false, &mut errors));
+            }
+            last_span = span;
+        }
+
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+        Ok((words, last_span))
+    }
+
+    /// Parses the rest of a `for ((init; condition; step))` loop, whose expressions are
+    /// `parts`, from the `((...))` at `span` on.
+    fn parse_arith_for(&mut self, parts: Vec<WordPart>, span: Span) -> Result<(Compound, Span), ParseErrors> {
+        let Ok([init, condition, step]) = <[_; 3]>::try_from(split_parts(parts, ';')) else {
+            return Err(ParseError::UnexpectedToken(span).into());
+        };
+
+        let mut errors = Vec::new();
+        let is_blank = |parts: &[WordPart]| {
+            parts.iter().all(|part| matches!(part, WordPart::Literal(text) if text.trim().is_empty()))
+        };
+        let condition = if is_blank(&condition) {
+            None
+        } else {
+            Some(Box::new(parse_word(condition, &mut errors)))
+        };
+        let init = Box::new(parse_word(init, &mut errors));
+        let step = Box::new(parse_word(step, &mut errors));
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+
+        let last_span = self.skip_separator().unwrap_or(span);
+        self.skip_newlines();
+        let (body, done_span) = self.parse_do_group(last_span)?;
+        Ok((Compound::ArithFor { init, condition, step, body: Box::new(body) }, done_span))
+    }
+
+    /// Takes a `;` if that's what's next, returning where it was.
+    fn skip_separator(&mut self) -> Option<Span> {
+        match self.tokens.next_if(|res| matches!(res, Ok((Token::AndThen, _)))) {
+            Some(Ok((_, span))) => Some(span),
+            _ => None,
+        }
+    }
+
+    /// Parses `expression [|| expression]...` inside `[[ ]]`, after whatever's at `after`.
+    fn parse_cond_or(&mut self, after: Span) -> Result<CondExpr, ParseErrors> {
+        let mut expr = self.parse_cond_and(after)?;
+
+        // `||` lexes as two pipes
+        while let Some(Ok((_, first))) = self.tokens.next_if(|res| matches!(res, Ok((Token::Pipe, _)))) {
+            let Some(Ok((_, second))) =
+                self.tokens.next_if(|res| matches!(res, Ok((Token::Pipe, span)) if span.start == first.end))
+            else {
+                return Err(ParseError::UnexpectedToken(first).into());
+            };
+            self.skip_newlines();
+            let right = self.parse_cond_and(first.to(second))?;
+            expr = CondExpr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_and(&mut self, after: Span) -> Result<CondExpr, ParseErrors> {
+        let mut expr = self.parse_cond_not(after)?;
+
+        while let Some(Ok((_, span))) = self.tokens.next_if(|res| matches!(res, Ok((Token::AndThenIf, _)))) {
+            self.skip_newlines();
+            let right = self.parse_cond_not(span)?;
+            expr = CondExpr::And(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_not(&mut self, after: Span) -> Result<CondExpr, ParseErrors> {
+        match self.next_reserved("!") {
+            Some(span) => Ok(CondExpr::Not(Box::new(self.parse_cond_not(span)?))),
+            None => self.parse_cond_primary(after),
+        }
+    }
+
+    /// Parses a test or a word inside `[[ ]]`, or an expression in parentheses.
+    fn parse_cond_primary(&mut self, after: Span) -> Result<CondExpr, ParseErrors> {
+        let parts = match self.tokens.next() {
+            None => return Err(ParseError::UnexpectedEof(after).into()),
+            Some(Err(e)) => return Err(e.into()),
+            // Parentheses lex as a subshell
+            Some(Ok((Token::SubShell(inner), span))) => {
+                let offset = span.end - 1 - inner.len();
+                let mut parser = Parser::new(Lexer::new_at(&inner, offset));
+                parser.skip_newlines();
+                let expr = parser.parse_cond_or(span)?;
+                parser.skip_newlines();
+                return match parser.tokens.next() {
+                    None => Ok(expr),
+                    Some(Ok((_, span))) => Err(ParseError::UnexpectedToken(span).into()),
+                    Some(Err(e)) => Err(e.into()),
+                };
+            }
+            Some(Ok((Token::Word(parts), span))) if !is_cond_end(&parts) => (parts, span),
+            Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+        };
+        let (parts, span) = parts;
+
+        if let [WordPart::Literal(op)] = &parts[..] {
+            let has_operand = matches!(self.tokens.peek(), Some(Ok((Token::Word(parts), _))) if !is_cond_end(parts));
+            if cond::is_unary_op(op) && has_operand {
+                let op = op.clone();
+                let operand = self.parse_cond_operand(span)?;
+                return Ok(CondExpr::Unary { op, operand });
+            }
+        }
+
+        let mut errors = Vec::new();
+        let left = parse_tilde_word(parts, false, &mut errors);
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+
+        let op = match self.tokens.peek() {
+            // `>` lexes as a redirection, but `<` is just a word
+            Some(Ok((Token::RedirOut, _))) => ">".to_string(),
+            Some(Ok((Token::Word(parts), _))) => match &parts[..] {
+                [WordPart::Literal(op)] if cond::is_binary_op(op) || op == "=~" => op.clone(),
+                _ => return Ok(CondExpr::Word(left)),
+            },
+            _ => return Ok(CondExpr::Word(left)),
+        };
+        let op_span = self.tokens.next().and_then(Result::ok).map_or(span, |(_, span)| span);
+        let right = self.parse_cond_operand(op_span)?;
+        Ok(CondExpr::Binary { left, op, right })
+    }
+
+    /// Parses the word after an operator inside `[[ ]]`, which is at `op_span`.
+    fn parse_cond_operand(&mut self, op_span: Span) -> Result<Arg, ParseErrors> {
+        match self.tokens.next() {
+            None => Err(ParseError::UnexpectedEof(op_span).into()),
+            Some(Err(e)) => Err(e.into()),
+            Some(Ok((Token::Word(parts), _))) if !is_cond_end(&parts) => {
+                let mut errors = Vec::new();
+                let operand = parse_tilde_word(parts, false, &mut errors);
+                if !errors.is_empty() {
+                    return Err(ParseErrors { errors, source: None });
+                }
+                Ok(operand)
+            }
+            Some(Ok((_, span))) => Err(ParseError::UnexpectedToken(span).into()),
+        }
+    }
+
+    /// Parses the rest of a `function name [()] body` after the `function` at `function_span`.
+    fn parse_function(&mut self, function_span: Span) -> Result<(Compound, Span), ParseErrors> {
+        let (name, name_span) = match self.tokens.next() {
+            None => return Err(ParseError::UnexpectedEof(function_span).into()),
+            Some(Err(e)) => return Err(e.into()),
+            Some(Ok((Token::Word(parts), span))) => match &parts[..] {
+                [WordPart::Literal(name)] => (name.clone(), span),
+                _ => return Err(ParseError::UnexpectedToken(span).into()),
+            },
+            Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+        };
+
+        // The parentheses are optional here
+        let after = match self.tokens.next_if(|res| matches!(res, Ok((Token::SubShell(parens), _)) if parens.trim().is_empty())) {
+            Some(Ok((_, span))) => span,
+            _ => name_span,
+        };
+        let (body, end_span) = self.parse_function_body(after)?;
+        Ok((Compound::Function { name, body: Rc::new(body) }, end_span))
+    }
+
+    /// Parses the compound command that's the body of a function, after whatever's at `after`,
+    /// returning it along with the span of the end of it.
+    fn parse_function_body(&mut self, after: Span) -> Result<(Command, Span), ParseErrors> {
+        self.skip_newlines();
+        match self.tokens.next() {
+            None => Err(ParseError::UnexpectedEof(after).into()),
+            Some(Err(e)) => Err(e.into()),
+            Some(Ok((token, span))) => match reserved_word(&token, &COMPOUND_STARTS) {
+                Some(word) => {
+                    let (compound, end_span) = self.parse_compound(word, span)?;
+                    let body = Command {
+                        compound: Some(compound),
+                        span: span.to(end_span),
+                        ..Default::default()
+                    };
+                    Ok((body, end_span))
+                }
+                None => Err(ParseError::UnexpectedToken(span).into()),
+            },
+        }
+    }
+
+    /// Parses the rest of a `case` command after the `case` at `case_span`, returning it along
+    /// with the span of its `esac`.
+    fn parse_case(&mut self, case_span: Span) -> Result<(Compound, Span), ParseErrors> {
+        let mut errors = Vec::new();
+        let (word, word_span) = match self.tokens.next() {
+            None => return Err(ParseError::UnexpectedEof(case_span).into()),
+            Some(Err(e)) => return Err(e.into()),
+            Some(Ok((Token::Word(parts), span))) => (Box::new(parse_tilde_word(parts, false, &mut errors)), span),
+            Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+        };
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+
+        self.skip_newlines();
+        let (_, mut last_span) = self.expect_reserved(&["in"], word_span)?;
+        let mut arms = Vec::new();
+
+        loop {
+            self.skip_newlines();
+            if let Some(esac_span) = self.next_reserved("esac") {
+                return Ok((Compound::Case { word, arms }, esac_span));
+            }
+
+            let (patterns, close_span) = match self.tokens.next_if(|res| matches!(res, Ok((Token::SubShell(_), _)))) {
+                // With a `(` in front, the patterns lex as a subshell
+                Some(Ok((Token::SubShell(patterns), span))) => {
+                    let offset = span.end - 1 - patterns.len();
+                    let (patterns, _) = Parser::new(Lexer::new_at(&patterns, offset)).parse_patterns(span, false)?;
+                    (patterns, span)
+                }
+                _ => self.parse_patterns(last_span, true)?,
+            };
+
+            let in_case_arm = mem::replace(&mut self.in_case_arm, true);
+            let commands = self.parse_commands(close_span);
+            self.in_case_arm = in_case_arm;
+            let commands = commands?;
+            let body = (!commands.is_empty()).then(|| chain(commands));
+            match self.tokens.next() {
+                Some(Ok((Token::CaseEnd(terminator), span))) => {
+                    arms.push(CaseArm { patterns, body, terminator });
+                    last_span = span;
+                }
+                // The last arm doesn't need a `;;`
+                Some(Ok((token, span))) if reserved_word(&token, &["esac"]).is_some() => {
+                    arms.push(CaseArm { patterns, body, terminator: CaseTerminator::Break });
+                    return Ok((Compound::Case { word, arms }, span));
+                }
+                Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(ParseError::UnexpectedEof(close_span).into()),
+            }
+        }
+    }
+
+    /// Parses a `case` arm's `pattern | pattern...`, up to the `)` after them if `closed`,
+    /// or else the end of the input. `after` is what came before them, to point at if
+    /// they're missing.
+    fn parse_patterns(&mut self, after: Span, closed: bool) -> Result<(Vec<Arg>, Span), ParseErrors> {
+        let mut errors = Vec::new();
+        let mut patterns = Vec::new();
+        let mut last_span = after;
+
+        loop {
+            match self.tokens.next() {
+                None if closed => return Err(ParseError::UnexpectedEof(last_span).into()),
+                None => return Err(ParseError::UnexpectedToken(last_span).into()),
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok((Token::Word(parts), span))) => {
+                    patterns.push(parse_tilde_word(parts, false, &mut errors));
+                    last_span = span;
+                }
+                Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+            }
+
+            match self.tokens.next() {
+                None if closed => return Err(ParseError::UnexpectedEof(last_span).into()),
+                None => break,
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok((Token::Pipe, span))) => last_span = span,
+                Some(Ok((Token::CloseParen, span))) if closed => {
+                    last_span = span;
+                    break;
+                }
+                Some(Ok((_, span))) => return Err(ParseError::UnexpectedToken(span).into()),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ParseErrors { errors, source: None });
+        }
+        Ok((patterns, last_span))
+    }
+
+    /// Parses the `do list; done` of a loop, returning the list and the span of the `done`.
+    /// `after` is what came before it, to point at if the input runs out.
+    fn parse_do_group(&mut self, after: Span) -> Result<(Command, Span), ParseErrors> {
+        let (_, do_span) = self.expect_reserved(&["do"], after)?;
+        let body = self.parse_list(do_span)?;
+        let (_, done_span) = self.expect_reserved(&["done"], do_span)?;
+        Ok((body, done_span))
+    }
+
+    /// Parses the rest of an `if` command after the `if` at `if_span`, returning it along
+    /// with the span of its `fi`.
+    fn parse_if(&mut self, if_span: Span) -> Result<(Compound, Span), ParseErrors> {
+        let mut branches = Vec::new();
+        let mut keyword_span = if_span;
+
+        loop {
+            let condition = self.parse_list(keyword_span)?;
+            let (_, then_span) = self.expect_reserved(&["then"], keyword_span)?;
+            let body = self.parse_list(then_span)?;
+            branches.push((condition, body));
+
+            let (word, span) = self.expect_reserved(&["elif", "else", "fi"], then_span)?;
+            keyword_span = span;
+            match word {
+                "elif" => continue,
+                "else" => {
+                    let otherwise = Some(Box::new(self.parse_list(span)?));
+                    let (_, fi_span) = self.expect_reserved(&["fi"], span)?;
+                    return Ok((Compound::If { branches, otherwise }, fi_span));
+                }
+                _ => return Ok((Compound::If { branches, otherwise: None }, span)),
+            }
+        }
+    }
+
+    /// Whether there are no more commands to parse, skipping any blank lines on the way.
+    pub fn at_end(&mut self) -> bool {
+        self.skip_newlines();
+        self.tokens.peek().is_none()
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.tokens.next_if(|res| matches!(res, Ok((Token::Newline, _)))).is_some() {}
+    }
+
+    /// Whether the next token ends the current line, or there isn't one.
+    fn at_line_end(&mut self) -> bool {
+        matches!(self.tokens.peek(), None | Some(Ok((Token::Newline, _))))
+    }
+
+    /// Parses the command that must come after the operator at `op_span`, which may be on a later line.
+    fn parse_command_after(&mut self, op_span: Span) -> Result<Command, ParseErrors> {
+        if self.at_end() {
+            return Err(ParseErrors {
+                errors: vec![ParseError::UnexpectedEof(op_span)],
+                source: None,
+            });
+        }
+        self.parse_command()
+    }
+
+    /// Parses the file name that must come after the redirection operator at `op_span`.
+    ///
+    /// Anything else is left for `parse_command` to deal with.
+    fn parse_redir_target(&mut self, op_span: Span) -> Result<(Vec<WordPart>, Span), ParseError> {
+        match self.tokens.next_if(|res| matches!(res, Ok((Token::Word(_), _)))) {
+            Some(Ok((Token::Word(parts), span))) => Ok((parts, span)),
+            _ => Err(ParseError::MissingFileName(op_span)),
+        }
+    }
+}
+
+/// Turns the pieces of a word from the lexer into a single `Arg`.
+///
+/// Anything wrong with the commands substituted into it goes in `errors`.
+fn parse_word(parts: Vec<WordPart>, errors: &mut Vec<ParseError>) -> Arg {
+    join_args(parse_word_parts(parts, errors))
+}
+
+/// Like `parse_word`, but for a whole word that can start with `~`, or a value being
+/// assigned, where a `~` can come after any `:` too.
+fn parse_tilde_word(parts: Vec<WordPart>, assignment: bool, errors: &mut Vec<ParseError>) -> Arg {
+    let args = parse_word_parts(parts, errors);
+    let count = args.len();
+    let mut split = Vec::new();
+
+    for (i, arg) in args.into_iter().enumerate() {
+        match arg {
+            Arg::Word(text) if i == 0 || assignment => {
+                split_tildes(&text, i == 0, assignment, i == count - 1, &mut split);
+            }
+            arg => split.push(arg),
+        }
+    }
+    join_args(split)
+}
+
+/// Splits any `~` prefixes out of literal text from a word.
+///
+/// A prefix runs up to the first `/` (or `:` in an assignment), and has to be unquoted
+/// all the way, so one running off the end of `text` only counts if the word ends there too.
+fn split_tildes(text: &str, word_start: bool, assignment: bool, word_end: bool, args: &mut Vec<Arg>) {
+    let mut literal = String::new();
+    let mut rest = text;
+    let mut can_start = word_start;
+
+    loop {
+        if let Some(after) = rest.strip_prefix('~').filter(|_| can_start) {
+            let end = after.find(|c| c == '/' || (assignment && c == ':'));
+            if end.is_some() || word_end {
+                let end = end.unwrap_or(after.len());
+                if !literal.is_empty() {
+                    args.push(Arg::Word(std::mem::take(&mut literal)));
+                }
+                args.push(Arg::Tilde(after[..end].to_string()));
+                rest = &after[end..];
+            }
+        }
+
+        // Only an assignment can have more than one
+        match rest.find(':').filter(|_| assignment) {
+            Some(colon) => {
+                literal.push_str(&rest[..=colon]);
+                rest = &rest[colon + 1..];
+                can_start = true;
+            }
+            None => {
+                literal.push_str(rest);
+                break;
+            }
+        }
+    }
+
+    if !literal.is_empty() {
+        args.push(Arg::Word(literal));
+    }
+}
+
+/// The single `Arg` for a word made of `args`.
+fn join_args(mut args: Vec<Arg>) -> Arg {
+    if args.len() == 1 {
+        args.remove(0)
+    } else {
+        Arg::Concat(args)
+    }
+}
+
+fn parse_word_parts(parts: Vec<WordPart>, errors: &mut Vec<ParseError>) -> Vec<Arg> {
+    parts
+        .into_iter()
+        .filter_map(|part| match part {
+            WordPart::Literal(text) => Some(Arg::Word(text)),
+            WordPart::Variable(param) => Some(Arg::Variable(param.map(|word| Box::new(parse_word(word, errors))))),
+            WordPart::SubShell { command, offset } => parse_subshell(&command, offset, errors).map(Arg::Subshell),
+            WordPart::Arith(parts) => Some(Arg::Arith(Box::new(parse_word(parts, errors)))),
+            WordPart::ProcSub { command, offset, output } => {
+                parse_subshell(&command, offset, errors).map(|command| Arg::ProcSub { command, output })
+            }
+            WordPart::Quoted(parts) => Some(Arg::Quoted(parse_word_parts(parts, errors))),
+        })
+        .collect()
+}
+
+/// The `NAME` of a word that starts with `NAME=`, if it's an assignment.
+fn assignment_name(parts: &[WordPart]) -> Option<&str> {
+    let Some(WordPart::Literal(text)) = parts.first() else {
+        return None;
+    };
+    let (name, _) = text.split_once('=')?;
+    is_name(name).then_some(name)
+}
+
+/// Whether a word is the `]]` at the end of a `[[ ]]`.
+fn is_cond_end(parts: &[WordPart]) -> bool {
+    matches!(parts, [WordPart::Literal(text)] if text == "]]")
+}
+
+/// Splits the pieces of a word at each `separator` in its literal text.
+fn split_parts(parts: Vec<WordPart>, separator: char) -> Vec<Vec<WordPart>> {
+    let mut pieces = vec![Vec::new()];
+
+    for part in parts {
+        match part {
+            WordPart::Literal(text) => {
+                for (i, piece) in text.split(separator).enumerate() {
+                    if i > 0 {
+                        pieces.push(Vec::new());
+                    }
+                    if !piece.is_empty() {
+                        pieces.last_mut().unwrap().push(WordPart::Literal(piece.to_string()));
+                    }
+                }
+            }
+            part => pieces.last_mut().unwrap().push(part),
+        }
+    }
+    pieces
+}
+
+/// Removes the first `len` bytes of literal text from the start of a word.
+fn strip_prefix(mut parts: Vec<WordPart>, len: usize) -> Vec<WordPart> {
+    if let Some(WordPart::Literal(text)) = parts.first_mut() {
+        text.drain(..len);
+        if text.is_empty() {
+            parts.remove(0);
+        }
+    }
+    parts
+}
+
+/// Whether `name` can be a variable's name: letters, digits and underscores, not starting with a digit.
+pub fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Parses the command inside a subshell, which starts `offset` bytes into the source.
+fn parse_subshell(command: &str, offset: usize, errors: &mut Vec<ParseError>) -> Option<Command> {
+    match Parser::new(Lexer::new_at(command, offset)).parse_all() {
+        Ok(command) => Some(command),
+        Err(errs) => {
+            errors.extend(errs);
+            None
+        }
+    }
+}
+
+/// Chains `commands` together as if they were joined by ';'.
+fn chain(commands: Vec<Command>) -> Command {
+    let mut commands = commands.into_iter().rev();
+    let mut command = commands.next().expect("there's at least one command to chain");
+    for mut before in commands {
+        before.chain_end().and_then = Some(AndThen {
+            conditional: false,
+            target: Box::new(command),
+        });
+        command = before;
+    }
+    command
 }
 
 impl Command {
+    /// Parses all of `input`, with each line running after the last as if they were joined by ';'.
     pub fn parse(input: impl AsRef<str>) -> Result<Self, ParseErrors> {
-        let lexer = Lexer::new(input.as_ref());
-        let mut parser = Parser::new(lexer);
-        parser.parse_command()
+        let input = input.as_ref();
+        Parser::new(Lexer::new(input))
+            .parse_all()
+            .map_err(|errors| errors.with_source(input))
+    }
+
+    /// The last command in this one's chain of pipes and `and_then`s.
+    fn chain_end(&mut self) -> &mut Command {
+        match self {
+            Command { pipe_to: Some(pipe_to), .. } => pipe_to.target.chain_end(),
+            Command { and_then: Some(and_then), .. } => and_then.target.chain_end(),
+            command => command,
+        }
     }
 }
@@ -1,5 +1,5 @@
 use libc::{c_char, c_int, pid_t};
-use std::{ffi::CString, io, os::fd::{AsRawFd, RawFd}};
+use std::{ffi::{CStr, CString}, io, os::fd::{AsRawFd, RawFd}};
 
 unsafe extern "C" {
     static environ: *const *const c_char;
@@ -22,8 +22,115 @@ pub(crate) fn fork() -> io::Result<ForkReturn> {
     }
 }
 
-pub(crate) fn exec<S: AsRef<str>>(pathname: &S, argv: &[&S]) -> io::Result<()> {
-    let pathname = CString::new(pathname.as_ref()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "BAD: pathname str had a null byte."))?;
+pub(crate) fn getpid() -> pid_t {
+    // getpid can't fail
+    unsafe { libc::getpid() }
+}
+
+/// Looks up the user called `name` in the passwd database, returning their home directory.
+pub(crate) fn getpwnam(name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut result = std::ptr::null_mut();
+    // Where the strings `passwd` points to live, grown until they fit
+    let mut buf = vec![0 as c_char; 1024];
+
+    loop {
+        let res = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        };
+        match res {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => break,
+            _ => return None,
+        }
+    }
+
+    // Safe since `result` isn't null, so `pw_dir` points into `buf`
+    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    Some(dir.to_string_lossy().into_owned())
+}
+
+/// Whether we may access `path` in the way `mode` (`libc::R_OK` and so on) asks about.
+pub(crate) fn access(path: &str, mode: c_int) -> bool {
+    let Ok(path) = CString::new(path) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+pub(crate) fn isatty(fd: RawFd) -> bool {
+    // isatty just says no for fds that aren't open
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Matches `text` against the POSIX extended regular expression `pattern`, returning what
+/// the whole match and each group in it matched ("" for groups that didn't take part),
+/// or `None` if it doesn't match. A pattern that won't compile gives the reason why.
+pub(crate) fn regex_match(pattern: &str, text: &str) -> Result<Option<Vec<String>>, String> {
+    let c_pattern = CString::new(pattern).map_err(|_| "pattern has a null byte".to_string())?;
+    let c_text = CString::new(text).map_err(|_| "text has a null byte".to_string())?;
+    let mut regex = unsafe { std::mem::zeroed::<libc::regex_t>() };
+
+    let res = unsafe { libc::regcomp(&mut regex, c_pattern.as_ptr(), libc::REG_EXTENDED) };
+    if res != 0 {
+        let mut buf = vec![0 as c_char; 256];
+        unsafe { libc::regerror(res, &regex, buf.as_mut_ptr(), buf.len()) };
+        // Safe since regerror always null-terminates what it writes
+        let message = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        return Err(message.to_string_lossy().into_owned());
+    }
+
+    let mut matches = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; count_groups(pattern) + 1];
+    let res = unsafe { libc::regexec(&regex, c_text.as_ptr(), matches.len(), matches.as_mut_ptr(), 0) };
+    unsafe { libc::regfree(&mut regex) };
+    if res != 0 {
+        return Ok(None);
+    }
+
+    let groups = matches
+        .iter()
+        .map(|m| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+            // The offsets are in bytes, which might not be on character boundaries
+            (Ok(start), Ok(end)) => String::from_utf8_lossy(&text.as_bytes()[start..end]).into_owned(),
+            _ => String::new(),
+        })
+        .collect();
+    Ok(Some(groups))
+}
+
+/// The number of groups in an extended regular expression, since `regex_t` keeps that private.
+fn count_groups(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' => count += 1,
+            // A bracket expression, where a ']' right at the start is part of it
+            '[' => {
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Replaces the current process with `pathname`, so this only returns if that fails.
+pub(crate) fn exec<S: AsRef<str>>(pathname: &S, argv: &[S]) -> io::Error {
+    let Ok(pathname) = CString::new(pathname.as_ref()) else {
+        return io::Error::new(io::ErrorKind::InvalidInput, "BAD: pathname str had a null byte.");
+    };
 
     // Store our CStrings
     let argv = argv
@@ -38,18 +145,14 @@ pub(crate) fn exec<S: AsRef<str>>(pathname: &S, argv: &[&S]) -> io::Result<()> {
         .collect::<Vec<_>>();
     argv_ptrs.push(std::ptr::null());
 
-    if unsafe { libc::execvpe(pathname.as_ptr(), argv_ptrs.as_ptr(), environ) } < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        unsafe {
-            std::hint::unreachable_unchecked();
-        }
-    }
+    // execvpe only ever returns to report an error
+    unsafe { libc::execvpe(pathname.as_ptr(), argv_ptrs.as_ptr(), environ) };
+    io::Error::last_os_error()
 }
 
 pub(crate) struct WaitReturn {
-    pid: pid_t,
-    status: WaitStatus,
+    pub pid: pid_t,
+    pub status: WaitStatus,
 }
 
 pub(crate) enum WaitStatus {
@@ -60,10 +163,20 @@ pub(crate) enum WaitStatus {
     Unknown
 }
 
-pub(crate) fn wait() -> io::Result<WaitReturn> {
-    use WaitStatus as WS;
-    use libc::{WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG, WIFSTOPPED, WSTOPSIG, WIFCONTINUED};
+impl WaitStatus {
+    /// The value `$?` takes when a child ends this way.
+    pub(crate) fn exit_code(&self) -> i32 {
+        use WaitStatus as WS;
 
+        match *self {
+            WS::Exited(code) => code,
+            WS::TermSignal(signal) | WS::Stopped(signal) => 128 + signal,
+            WS::Continued | WS::Unknown => 0,
+        }
+    }
+}
+
+pub(crate) fn wait() -> io::Result<WaitReturn> {
     let mut stat_code = 0i32;
 
     let res = unsafe { libc::wait(&raw mut stat_code) };
@@ -71,21 +184,37 @@ pub(crate) fn wait() -> io::Result<WaitReturn> {
     if res < 0 {
         Err(io::Error::last_os_error())
     } else {
-        let pid = res;
-
-        let status = if WIFEXITED(stat_code) {
-            WS::Exited(WEXITSTATUS(stat_code))
-        } else if WIFSIGNALED(stat_code) {
-            WS::TermSignal(WTERMSIG(stat_code))
-        } else if WIFSTOPPED(stat_code) {
-            WS::Stopped(WSTOPSIG(stat_code))
-        } else if WIFCONTINUED(stat_code) {
-            WS::Continued
-        } else {
-            WS::Unknown
-        };
+        Ok(WaitReturn{pid: res, status: wait_status(stat_code)})
+    }
+}
+
+/// Like `wait`, but for the child `pid` in particular.
+pub(crate) fn waitpid(pid: pid_t) -> io::Result<WaitReturn> {
+    let mut stat_code = 0i32;
+
+    let res = unsafe { libc::waitpid(pid, &raw mut stat_code, 0) };
 
-        Ok(WaitReturn{pid, status})
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(WaitReturn{pid: res, status: wait_status(stat_code)})
+    }
+}
+
+fn wait_status(stat_code: c_int) -> WaitStatus {
+    use WaitStatus as WS;
+    use libc::{WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG, WIFSTOPPED, WSTOPSIG, WIFCONTINUED};
+
+    if WIFEXITED(stat_code) {
+        WS::Exited(WEXITSTATUS(stat_code))
+    } else if WIFSIGNALED(stat_code) {
+        WS::TermSignal(WTERMSIG(stat_code))
+    } else if WIFSTOPPED(stat_code) {
+        WS::Stopped(WSTOPSIG(stat_code))
+    } else if WIFCONTINUED(stat_code) {
+        WS::Continued
+    } else {
+        WS::Unknown
     }
 }
 
@@ -106,6 +235,55 @@ pub(crate) fn pipe() -> io::Result<Pipe> {
     }
 }
 
+/// Reads from `fd` until end of file.
+pub(crate) fn read_to_end(fd: RawFd) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let res = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        match res {
+            0 => return Ok(data),
+            ..0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            _ => data.extend_from_slice(&buf[..res as usize]),
+        }
+    }
+}
+
+/// Reads from `fd` up to and including the next newline, appending to `line`, and
+/// returns how many bytes were read (0 at end of file).
+///
+/// This goes a byte at a time so nothing past the line is taken from `fd`, which
+/// leaves the rest for whatever gets run next to read.
+pub(crate) fn read_line(fd: RawFd, line: &mut Vec<u8>) -> io::Result<usize> {
+    let start = line.len();
+    let mut byte = 0u8;
+
+    loop {
+        let res = unsafe { libc::read(fd, (&raw mut byte).cast(), 1) };
+        match res {
+            0 => return Ok(line.len() - start),
+            ..0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            _ => {
+                line.push(byte);
+                if byte == b'\n' {
+                    return Ok(line.len() - start);
+                }
+            }
+        }
+    }
+}
+
 pub(crate) fn dup2<F: AsRawFd>(oldfd: F, newfd: F) -> io::Result<()> {
     if unsafe { libc::dup2(oldfd.as_raw_fd(), newfd.as_raw_fd()) } < 0 {
         Err(io::Error::last_os_error())
@@ -114,6 +292,16 @@ pub(crate) fn dup2<F: AsRawFd>(oldfd: F, newfd: F) -> io::Result<()> {
     }
 }
 
+/// Duplicates `fd` onto a new descriptor that's closed in any program we run.
+pub(crate) fn dup_cloexec<F: AsRawFd>(fd: F) -> io::Result<RawFd> {
+    let res = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
 pub(crate) fn close<F: AsRawFd>(fd: F) -> io::Result<()> {
     if unsafe { libc::close(fd.as_raw_fd()) } < 0 {
         Err(io::Error::last_os_error())
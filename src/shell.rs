@@ -0,0 +1,1095 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::process::exit;
+use std::rc::Rc;
+
+use libc::pid_t;
+
+use crate::cond;
+use crate::glob::GlobOptions;
+use crate::lexer::Lexer;
+use crate::parser::{is_name, Arg, CaseArm, CaseTerminator, Command, Compound, Parser, RedirType};
+use crate::safe_wrappers::{
+    close, dup2, dup_cloexec, exec, fork, getpid, pipe, read_to_end, wait, waitpid, ForkReturn, Pipe, WaitReturn,
+};
+
+/// The commands the shell runs itself rather than looking for a program.
+const BUILTINS: [&str; 11] = ["break", "continue", "cd", "exit", "export", "local", "return", "shopt", "test", "[", "unset"];
+
+fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name)
+}
+
+/// Something that cuts the rest of a script short, unwinding until something handles it.
+#[derive(Debug)]
+pub enum Jump {
+    /// `exit`, with the status to exit with
+    Exit(i32),
+    /// An error that abandons the rest of the command line, like a failed `${VAR:?}`.
+    /// Scripts exit with the status, but at the prompt we just carry on.
+    Error(i32),
+    /// `break`, out of this many loops
+    Break(usize),
+    /// `continue`, on to the next time round the loop this many loops out
+    Continue(usize),
+    /// `return`, from the function that's running, with its status
+    Return(i32),
+}
+
+impl Jump {
+    /// The status to exit with if nothing handles the jump.
+    pub fn status(&self) -> i32 {
+        match *self {
+            Jump::Exit(status) | Jump::Error(status) | Jump::Return(status) => status,
+            Jump::Break(_) | Jump::Continue(_) => 0,
+        }
+    }
+}
+
+/// What a loop does after a `break` or `continue` that's aimed at it.
+enum LoopControl {
+    Break,
+    Continue,
+}
+
+impl LoopControl {
+    /// What to do about `jump` in a loop, or the jump to pass on to whatever the loop is in.
+    fn from_jump(jump: Jump) -> Result<Self, Jump> {
+        match jump {
+            Jump::Break(1) => Ok(LoopControl::Break),
+            Jump::Continue(1) => Ok(LoopControl::Continue),
+            Jump::Break(levels) => Err(Jump::Break(levels - 1)),
+            Jump::Continue(levels) => Err(Jump::Continue(levels - 1)),
+            jump => Err(jump),
+        }
+    }
+}
+
+/// The exit status of a finished command, or the jump it made instead.
+pub type RunResult = Result<i32, Jump>;
+
+pub struct Shell {
+    /// `$0`: the name of the shell, or of the script it's running
+    pub name: String,
+    /// `$1`, `$2`, and so on
+    pub positional: Vec<String>,
+    /// `$?`: the exit status of the last command
+    pub last_status: i32,
+    /// `$$`: the process ID of the shell, even in subshells
+    pub pid: pid_t,
+    /// What `shopt` has set for matching paths
+    pub glob_options: GlobOptions,
+    vars: HashMap<String, Variable>,
+    /// Arrays, which can only be set by the shell itself, like `BASH_REMATCH`
+    arrays: HashMap<String, Vec<String>>,
+    /// The functions that have been defined, by name
+    functions: HashMap<String, Rc<Command>>,
+    /// What each function call that's running made local, along with what to put back
+    /// (if anything) when it returns
+    local_frames: Vec<HashMap<String, Option<Variable>>>,
+    /// How many loops we're running inside of (in the current function), for `break`
+    /// and `continue`
+    loop_depth: usize,
+    /// The processes running `<(...)` and `>(...)` for the command being run, along with
+    /// our ends of their pipes
+    proc_subs: Vec<(pid_t, RawFd)>,
+    /// The status of the last command substitution in the command being expanded, which
+    /// becomes the command's own if it turns out to be nothing but assignments
+    subst_status: Option<i32>,
+}
+
+struct Variable {
+    value: String,
+    /// Whether commands we run get the variable in their environment
+    exported: bool,
+}
+
+impl Shell {
+    pub fn new(name: String, positional: Vec<String>) -> Self {
+        // Everything we were given in our environment gets passed on
+        let vars = env::vars()
+            .map(|(name, value)| (name, Variable { value, exported: true }))
+            .collect();
+
+        let mut shell = Shell {
+            name,
+            positional,
+            last_status: 0,
+            pid: getpid(),
+            glob_options: GlobOptions::default(),
+            vars,
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            local_frames: Vec::new(),
+            loop_depth: 0,
+            proc_subs: Vec::new(),
+            subst_status: None,
+        };
+        if shell.var("PWD").is_none() {
+            if let Ok(dir) = env::current_dir() {
+                shell.set_var("PWD", dir.to_string_lossy().into_owned());
+            }
+        }
+        shell
+    }
+
+    /// The value of a variable, or the first element of an array.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        match self.vars.get(name) {
+            Some(var) => Some(&var.value),
+            None => self.arrays.get(name)?.first().map(String::as_str),
+        }
+    }
+
+    /// The elements of an array, where a variable counts as an array of one.
+    pub fn array(&self, name: &str) -> Vec<String> {
+        match self.arrays.get(name) {
+            Some(elements) => elements.clone(),
+            None => self.var(name).map(|value| vec![value.to_string()]).unwrap_or_default(),
+        }
+    }
+
+    /// Sets an array, replacing any variable of the same name.
+    pub fn set_array(&mut self, name: &str, elements: Vec<String>) {
+        self.vars.remove(name);
+        self.arrays.insert(name.to_string(), elements);
+    }
+
+    /// Sets a variable, keeping it exported if it already was.
+    pub fn set_var(&mut self, name: &str, value: String) {
+        self.arrays.remove(name);
+        match self.vars.get_mut(name) {
+            Some(var) => var.value = value,
+            None => {
+                let var = Variable { value, exported: false };
+                self.vars.insert(name.to_string(), var);
+            }
+        }
+    }
+
+    /// The characters that separate fields, from `IFS`.
+    pub fn ifs(&self) -> &str {
+        self.var("IFS").unwrap_or(" \t\n")
+    }
+
+    pub fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+        self.arrays.remove(name);
+    }
+
+    /// Makes a variable part of the environment of the commands we run, creating it if need be.
+    pub fn export_var(&mut self, name: &str) {
+        self.vars
+            .entry(name.to_string())
+            .or_insert_with(|| Variable { value: String::new(), exported: false })
+            .exported = true;
+    }
+
+    /// Makes our (process's) environment match the variables we've exported.
+    ///
+    /// This is only for just before running another program, since changing the environment
+    /// isn't safe once there are other threads.
+    fn sync_env(&self) {
+        for (name, _) in env::vars_os() {
+            let keep = name
+                .to_str()
+                .and_then(|name| self.vars.get(name))
+                .is_some_and(|var| var.exported);
+            if !keep {
+                env::remove_var(name);
+            }
+        }
+
+        for (name, var) in &self.vars {
+            if var.exported {
+                env::set_var(name, &var.value);
+            }
+        }
+    }
+
+    /// Runs a whole script, returning the status the shell should exit with.
+    ///
+    /// Each line is parsed just before it runs, so a syntax error stops the script
+    /// without keeping the lines before it from running.
+    pub fn run_script(&mut self, source: &str) -> i32 {
+        let mut parser = Parser::new(Lexer::new(source));
+
+        while !parser.at_end() {
+            let result = match parser.parse_command() {
+                Ok(command) => self.run_list(&command),
+                Err(errors) => {
+                    eprintln!("{}: {}", self.name, errors.with_source(source));
+                    Err(Jump::Exit(2))
+                }
+            };
+
+            if let Err(jump) = result {
+                return jump.status();
+            }
+        }
+
+        self.last_status
+    }
+
+    /// Runs `cmd` and everything chained after it with `;` and `&&`.
+    pub fn run_list(&mut self, cmd: &Command) -> RunResult {
+        let mut cmd = cmd;
+        let mut skip = false;
+
+        loop {
+            if !skip {
+                self.last_status = self.run_pipeline(cmd)?;
+            }
+
+            // Whatever comes next hangs off the last command of the pipeline
+            let mut last = cmd;
+            while let Some(pipe_to) = &last.pipe_to {
+                last = &pipe_to.target;
+            }
+
+            match &last.and_then {
+                Some(and_then) => {
+                    skip = and_then.conditional && self.last_status != 0;
+                    cmd = &and_then.target;
+                }
+                None => return Ok(self.last_status),
+            }
+        }
+    }
+
+    fn run_pipeline(&mut self, cmd: &Command) -> RunResult {
+        if cmd.pipe_to.is_none() {
+            return self.run_simple(cmd);
+        }
+
+        match self.spawn_pipeline(cmd).and_then(|pids| wait_for(&pids)) {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                eprintln!("{}: {e}", self.name);
+                Ok(1)
+            }
+        }
+    }
+
+    /// Forks off a process for each command in the pipeline starting at `cmd`.
+    fn spawn_pipeline(&mut self, cmd: &Command) -> io::Result<Vec<pid_t>> {
+        let mut pids = Vec::new();
+        let mut read_from: Option<RawFd> = None;
+        let mut stage = Some(cmd);
+
+        while let Some(cmd) = stage {
+            let pipe_out = match &cmd.pipe_to {
+                Some(pipe_to) => Some((pipe()?, &pipe_to.pipe_type)),
+                None => None,
+            };
+
+            match fork()? {
+                ForkReturn::Child => {
+                    if let Err(e) = connect_pipes(read_from, pipe_out.as_ref()) {
+                        eprintln!("{}: {e}", self.name);
+                        exit(1);
+                    }
+                    self.run_forked(cmd);
+                }
+                ForkReturn::Parent(pid) => {
+                    pids.push(pid);
+                    if let Some(read_from) = read_from.take() {
+                        close(read_from)?;
+                    }
+                    if let Some((pipe, _)) = pipe_out {
+                        close(pipe.write_fd)?;
+                        read_from = Some(pipe.read_fd);
+                    }
+                }
+            }
+
+            stage = cmd.pipe_to.as_ref().map(|pipe_to| pipe_to.target.as_ref());
+        }
+
+        Ok(pids)
+    }
+
+    /// Runs a single command (ignoring what it's piped to) in the shell's own process.
+    fn run_simple(&mut self, cmd: &Command) -> RunResult {
+        // Any process substitutions in the command are done with once it is
+        let proc_subs_before = self.proc_subs.len();
+        let result = self.run_simple_inner(cmd);
+        self.finish_proc_subs(proc_subs_before);
+        result
+    }
+
+    fn run_simple_inner(&mut self, cmd: &Command) -> RunResult {
+        if let Some(compound) = &cmd.compound {
+            return self.with_redirects(cmd, |shell| shell.run_compound(compound));
+        }
+
+        self.subst_status = None;
+        let args = self.expand_words(&cmd.argv)?;
+        let assignments = self.expand_assignments(cmd)?;
+        if args.is_empty() {
+            // With no command to run, assignments are for the shell itself
+            for (name, value) in assignments {
+                self.set_var(&name, value);
+            }
+            return Ok(self.subst_status.unwrap_or(0));
+        }
+
+        if let Some(body) = self.functions.get(&args[0]).cloned() {
+            return self.with_redirects(cmd, |shell| shell.call_function(&body, &args, assignments));
+        }
+        if is_builtin(&args[0]) {
+            return self.with_redirects(cmd, |shell| shell.run_builtin(&args).expect("Checked it's a builtin"));
+        }
+
+        let spawned = fork().and_then(|fork_return| match fork_return {
+            ForkReturn::Child => {
+                self.export_assignments(assignments);
+                if let Err(jump) = self.redirect(cmd) {
+                    exit(jump.status());
+                }
+                self.exec_external(&args)
+            }
+            ForkReturn::Parent(pid) => wait_for(&[pid]),
+        });
+
+        match spawned {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                eprintln!("{}: {e}", self.name);
+                Ok(1)
+            }
+        }
+    }
+
+    /// Runs a single command in a process that was forked just for it, then exits.
+    fn run_forked(&mut self, cmd: &Command) -> ! {
+        if let Err(jump) = self.redirect(cmd) {
+            exit(jump.status());
+        }
+
+        if let Some(compound) = &cmd.compound {
+            let status = self.run_compound(compound).unwrap_or_else(|jump| jump.status());
+            let _ = io::stdout().flush();
+            exit(status);
+        }
+
+        self.subst_status = None;
+        let expanded = self
+            .expand_words(&cmd.argv)
+            .and_then(|args| Ok((args, self.expand_assignments(cmd)?)));
+        let (args, assignments) = match expanded {
+            Ok(expanded) => expanded,
+            Err(jump) => exit(jump.status()),
+        };
+        // We're in our own process, so the assignments can't affect anything else
+        self.export_assignments(assignments);
+        if args.is_empty() {
+            exit(self.subst_status.unwrap_or(0));
+        }
+
+        let status = match self.functions.get(&args[0]).cloned() {
+            Some(body) => self.call_function(&body, &args, Vec::new()).unwrap_or_else(|jump| jump.status()),
+            None => match self.run_builtin(&args) {
+                Some(Ok(status)) => status,
+                Some(Err(jump)) => jump.status(),
+                None => self.exec_external(&args),
+            },
+        };
+
+        let _ = io::stdout().flush();
+        exit(status);
+    }
+
+    fn run_compound(&mut self, compound: &Compound) -> RunResult {
+        match compound {
+            Compound::Arith(expr) => Ok(if self.eval_arith(expr)? != 0 { 0 } else { 1 }),
+            Compound::If { branches, otherwise } => {
+                for (condition, body) in branches {
+                    if self.run_list(condition)? == 0 {
+                        return self.run_list(body);
+                    }
+                }
+                match otherwise {
+                    Some(otherwise) => self.run_list(otherwise),
+                    None => Ok(0),
+                }
+            }
+            Compound::While { until, condition, body } => self.in_loop(|shell| shell.run_while(*until, condition, body)),
+            Compound::For { name, words, body } => {
+                let values = match words {
+                    Some(words) => self.expand_words(words)?,
+                    None => self.positional.clone(),
+                };
+                self.in_loop(|shell| shell.run_for(name, &values, body))
+            }
+            Compound::ArithFor { init, condition, step, body } => {
+                self.in_loop(|shell| shell.run_arith_for(init, condition.as_deref(), step, body))
+            }
+            Compound::Case { word, arms } => self.run_case(word, arms),
+            Compound::Group(body) => self.run_list(body),
+            Compound::Conditional(expr) => Ok(if self.eval_cond(expr)? { 0 } else { 1 }),
+            Compound::Function { name, body } => {
+                self.functions.insert(name.clone(), Rc::clone(body));
+                Ok(0)
+            }
+        }
+    }
+
+    /// Calls the function `body` with `args`, the first of which is its name, and with
+    /// `assignments` only lasting until it returns.
+    fn call_function(&mut self, body: &Command, args: &[String], assignments: Vec<(String, String)>) -> RunResult {
+        let positional = mem::replace(&mut self.positional, args[1..].to_vec());
+        // Loops outside the function are out of reach of `break` and `continue`
+        let loop_depth = mem::replace(&mut self.loop_depth, 0);
+        self.local_frames.push(HashMap::new());
+        for (name, value) in assignments {
+            self.make_local(&name);
+            self.set_var(&name, value);
+            self.export_var(&name);
+        }
+
+        let result = match self.run_list(body) {
+            Err(Jump::Return(status)) => Ok(status),
+            result => result,
+        };
+
+        for (name, var) in self.local_frames.pop().unwrap_or_default() {
+            match var {
+                Some(var) => self.vars.insert(name, var),
+                None => self.vars.remove(&name),
+            };
+        }
+        self.loop_depth = loop_depth;
+        self.positional = positional;
+        result
+    }
+
+    /// Makes `name` local to the function call that's running, starting out unset, so
+    /// it's put back how it was when the call returns.
+    fn make_local(&mut self, name: &str) {
+        if let Some(frame) = self.local_frames.last_mut() {
+            if !frame.contains_key(name) {
+                frame.insert(name.to_string(), self.vars.remove(name));
+            }
+        }
+    }
+
+    fn run_case(&mut self, word: &Arg, arms: &[CaseArm]) -> RunResult {
+        let word = self.expand_word(word)?;
+        let mut status = 0;
+        // Whether the last arm ran and fell through into this one
+        let mut falling_through = false;
+
+        for arm in arms {
+            if !falling_through && !self.case_arm_matches(&word, arm)? {
+                continue;
+            }
+
+            status = match &arm.body {
+                Some(body) => self.run_list(body)?,
+                None => 0,
+            };
+            match arm.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => falling_through = true,
+                CaseTerminator::Continue => falling_through = false,
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Whether any of `arm`'s patterns match `word`, expanding them in order until one does.
+    fn case_arm_matches(&mut self, word: &str, arm: &CaseArm) -> Result<bool, Jump> {
+        for pattern in &arm.patterns {
+            if self.expand_pattern(pattern)?.matches(word) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs `run` as a loop, which `break` and `continue` can then be used in.
+    fn in_loop(&mut self, run: impl FnOnce(&mut Self) -> RunResult) -> RunResult {
+        self.loop_depth += 1;
+        let result = run(self);
+        self.loop_depth -= 1;
+        result
+    }
+
+    /// Runs a loop's body, setting `status` to its status, and returns whether the loop
+    /// should go round again.
+    fn run_loop_body(&mut self, body: &Command, status: &mut i32) -> Result<bool, Jump> {
+        match self.run_list(body) {
+            Ok(body_status) => *status = body_status,
+            Err(jump) => {
+                *status = 0;
+                match LoopControl::from_jump(jump)? {
+                    LoopControl::Break => return Ok(false),
+                    LoopControl::Continue => {}
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn run_while(&mut self, until: bool, condition: &Command, body: &Command) -> RunResult {
+        let mut status = 0;
+
+        loop {
+            match self.run_list(condition) {
+                Ok(condition_status) if (condition_status == 0) != until => {}
+                Ok(_) => break,
+                Err(jump) => match LoopControl::from_jump(jump)? {
+                    LoopControl::Break => return Ok(0),
+                    LoopControl::Continue => continue,
+                },
+            }
+
+            if !self.run_loop_body(body, &mut status)? {
+                break;
+            }
+        }
+
+        Ok(status)
+    }
+
+    fn run_for(&mut self, name: &str, values: &[String], body: &Command) -> RunResult {
+        let mut status = 0;
+
+        for value in values {
+            self.set_var(name, value.clone());
+            if !self.run_loop_body(body, &mut status)? {
+                break;
+            }
+        }
+
+        Ok(status)
+    }
+
+    fn run_arith_for(&mut self, init: &Arg, condition: Option<&Arg>, step: &Arg, body: &Command) -> RunResult {
+        let mut status = 0;
+
+        self.eval_arith(init)?;
+        while condition.map_or(Ok(1), |condition| self.eval_arith(condition))? != 0 {
+            if !self.run_loop_body(body, &mut status)? {
+                break;
+            }
+            self.eval_arith(step)?;
+        }
+
+        Ok(status)
+    }
+
+    /// Starts `cmd` running with a pipe to or (if it's `output`) from it, returning the path
+    /// of our end, which stays open until the command using it finishes.
+    pub fn process_substitution(&mut self, cmd: &Command, output: bool) -> Result<String, Jump> {
+        let spawned = pipe().and_then(|pipe| {
+            let (ours, theirs, their_fd) = if output {
+                (pipe.write_fd, pipe.read_fd, libc::STDIN_FILENO)
+            } else {
+                (pipe.read_fd, pipe.write_fd, libc::STDOUT_FILENO)
+            };
+
+            match fork()? {
+                ForkReturn::Child => {
+                    // The other substitutions' pipes aren't ours to hold open, or their
+                    // commands would never see the end of their input
+                    for (_, fd) in self.proc_subs.drain(..) {
+                        let _ = close(fd);
+                    }
+
+                    let connected = close(ours).and_then(|()| dup2(theirs, their_fd)).and_then(|()| close(theirs));
+                    if let Err(e) = connected {
+                        eprintln!("{}: {e}", self.name);
+                        exit(1);
+                    }
+
+                    let status = self.run_list(cmd).unwrap_or_else(|jump| jump.status());
+                    let _ = io::stdout().flush();
+                    exit(status);
+                }
+                ForkReturn::Parent(pid) => {
+                    close(theirs)?;
+                    self.proc_subs.push((pid, ours));
+                    Ok(format!("/dev/fd/{ours}"))
+                }
+            }
+        });
+
+        spawned.map_err(|e| {
+            eprintln!("{}: {e}", self.name);
+            Jump::Error(1)
+        })
+    }
+
+    /// Closes our ends of the pipes for the process substitutions started since there
+    /// were `keep` of them, then waits for their processes to finish.
+    ///
+    /// Every pipe is closed before waiting on anything, since a command reading from one
+    /// won't finish until it's closed.
+    fn finish_proc_subs(&mut self, keep: usize) {
+        let finished = self.proc_subs.split_off(keep);
+        for &(_, fd) in &finished {
+            let _ = close(fd);
+        }
+        for (pid, _) in finished {
+            // It may have been reaped already while waiting for something else
+            let _ = waitpid(pid);
+        }
+    }
+
+    /// Runs `cmd` in a subshell, returning what it printed minus any trailing newlines.
+    pub fn command_output(&mut self, cmd: &Command) -> String {
+        match self.capture_output(cmd) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("{}: {e}", self.name);
+                String::new()
+            }
+        }
+    }
+
+    fn capture_output(&mut self, cmd: &Command) -> io::Result<String> {
+        let pipe = pipe()?;
+
+        match fork()? {
+            ForkReturn::Child => {
+                let connected = close(pipe.read_fd)
+                    .and_then(|()| dup2(pipe.write_fd, libc::STDOUT_FILENO))
+                    .and_then(|()| close(pipe.write_fd));
+                if let Err(e) = connected {
+                    eprintln!("{}: {e}", self.name);
+                    exit(1);
+                }
+
+                let status = match self.run_list(cmd) {
+                    Ok(status) => status,
+                    Err(jump) => jump.status(),
+                };
+                let _ = io::stdout().flush();
+                exit(status);
+            }
+            ForkReturn::Parent(pid) => {
+                close(pipe.write_fd)?;
+                let output = read_to_end(pipe.read_fd);
+                close(pipe.read_fd)?;
+                // Seen by `$?` straight away, like in `x=$(false) y=$?`
+                self.last_status = wait_for(&[pid])?;
+                self.subst_status = Some(self.last_status);
+
+                let output = String::from_utf8_lossy(&output?).into_owned();
+                Ok(output.trim_end_matches('\n').to_string())
+            }
+        }
+    }
+
+    /// Expands the values of `cmd`'s assignments, pairing them up with the variables' names.
+    fn expand_assignments(&mut self, cmd: &Command) -> Result<Vec<(String, String)>, Jump> {
+        cmd.assignments
+            .iter()
+            .map(|assignment| Ok((assignment.name.clone(), self.expand_word(&assignment.value)?)))
+            .collect()
+    }
+
+    /// Sets and exports the variables assigned before a command, for when it's running in its own process.
+    fn export_assignments(&mut self, assignments: Vec<(String, String)>) {
+        for (name, value) in assignments {
+            self.set_var(&name, value);
+            self.export_var(&name);
+        }
+    }
+
+    /// Sets up `cmd`'s redirections, for when it's running in its own process.
+    fn redirect(&mut self, cmd: &Command) -> Result<(), Jump> {
+        for redir in &cmd.redirect_to {
+            match redir.redirect_type {
+                RedirType::HereDoc | RedirType::HereString => {
+                    let mut text = self.expand_word(&redir.target)?;
+                    if redir.redirect_type == RedirType::HereString {
+                        text.push('\n');
+                    }
+                    if let Err(e) = feed_stdin(&text) {
+                        eprintln!("{}: {e}", self.name);
+                        return Err(Jump::Error(1));
+                    }
+                }
+                // Output still goes wherever it's piped to, since files aren't supported yet
+                RedirType::Stdout | RedirType::Stderr | RedirType::Both => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` in the shell's own process with `cmd`'s redirections in place, putting
+    /// stdin back afterwards.
+    fn with_redirects(&mut self, cmd: &Command, f: impl FnOnce(&mut Self) -> RunResult) -> RunResult {
+        if cmd.redirect_to.is_empty() {
+            return f(self);
+        }
+
+        let saved = dup_cloexec(libc::STDIN_FILENO).map_err(|e| {
+            eprintln!("{}: {e}", self.name);
+            Jump::Error(1)
+        })?;
+        let result = self.redirect(cmd).and_then(|()| f(self));
+
+        let restored = dup2(saved, libc::STDIN_FILENO).and_then(|()| close(saved));
+        if let Err(e) = restored {
+            eprintln!("{}: {e}", self.name);
+            return Err(Jump::Error(1));
+        }
+        result
+    }
+
+    /// Replaces this (forked) process with the program `args` names.
+    fn exec_external(&self, args: &[String]) -> ! {
+        self.sync_env();
+        let e = exec(&args[0], args);
+        eprintln!("Error running {}: {e}", args[0]);
+        exit(if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 });
+    }
+
+    /// Runs `args` if it's a builtin command (one of `BUILTINS`), which has to happen in the
+    /// shell's own process.
+    fn run_builtin(&mut self, args: &[String]) -> Option<RunResult> {
+        match args[0].as_str() {
+            "break" | "continue" => Some(self.builtin_loop_control(&args[0], &args[1..])),
+            "cd" => Some(self.builtin_cd(&args[1..])),
+            "exit" => Some(self.builtin_exit(&args[1..])),
+            "export" => Some(self.builtin_export(&args[1..])),
+            "local" => Some(self.builtin_local(&args[1..])),
+            "return" => Some(self.builtin_return(&args[1..])),
+            "shopt" => Some(self.builtin_shopt(&args[1..])),
+            "test" | "[" => Some(self.builtin_test(&args[0], &args[1..])),
+            "unset" => Some(self.builtin_unset(&args[1..])),
+            _ => None,
+        }
+    }
+
+    /// `break [N]` or `continue [N]`, for the `N`th loop out from the one we're in.
+    fn builtin_loop_control(&mut self, name: &str, args: &[String]) -> RunResult {
+        let levels = match args {
+            [] => 1,
+            [levels] => match levels.parse::<usize>() {
+                Ok(levels) if levels > 0 => levels,
+                Ok(_) => {
+                    eprintln!("{}: {name}: {levels}: loop count out of range", self.name);
+                    return Ok(1);
+                }
+                Err(_) => {
+                    eprintln!("{}: {name}: {levels}: numeric argument required", self.name);
+                    return Ok(1);
+                }
+            },
+            _ => {
+                eprintln!("{}: {name}: too many arguments", self.name);
+                return Ok(1);
+            }
+        };
+
+        if self.loop_depth == 0 {
+            eprintln!("{}: {name}: only meaningful in a loop", self.name);
+            return Ok(0);
+        }
+
+        // Asking for more loops than there are means all of them
+        let levels = levels.min(self.loop_depth);
+        Err(if name == "break" { Jump::Break(levels) } else { Jump::Continue(levels) })
+    }
+
+    /// `cd [DIR]`, where `DIR` defaults to `HOME`, and `-` means `OLDPWD`.
+    fn builtin_cd(&mut self, args: &[String]) -> RunResult {
+        // Where the directory came from, if it wasn't given outright
+        let (dir, source) = match args {
+            [] => (self.var("HOME"), "HOME"),
+            [dir] if dir == "-" => (self.var("OLDPWD"), "OLDPWD"),
+            [dir] => (Some(dir.as_str()), ""),
+            _ => {
+                eprintln!("{}: cd: too many arguments", self.name);
+                return Ok(1);
+            }
+        };
+        let Some(dir) = dir.map(str::to_string) else {
+            eprintln!("{}: cd: {source} not set", self.name);
+            return Ok(1);
+        };
+
+        if let Err(e) = env::set_current_dir(&dir) {
+            eprintln!("{}: cd: {dir}: {e}", self.name);
+            return Ok(1);
+        }
+
+        let pwd = env::current_dir().map_or(dir, |dir| dir.to_string_lossy().into_owned());
+        // Going back somewhere, so say where that is
+        if source == "OLDPWD" {
+            println!("{pwd}");
+        }
+        if let Some(old) = self.var("PWD").map(str::to_string) {
+            self.set_var("OLDPWD", old);
+        }
+        self.set_var("PWD", pwd);
+        Ok(0)
+    }
+
+    fn builtin_exit(&mut self, args: &[String]) -> RunResult {
+        match args {
+            [] => Err(Jump::Exit(self.last_status)),
+            [status] => match status.parse() {
+                Ok(status) => Err(Jump::Exit(status)),
+                Err(_) => {
+                    eprintln!("{}: exit: {status}: numeric argument required", self.name);
+                    Err(Jump::Exit(2))
+                }
+            },
+            _ => {
+                eprintln!("{}: exit: too many arguments", self.name);
+                Ok(1)
+            }
+        }
+    }
+
+    /// `export NAME[=value]...`, or just `export` to list what's exported.
+    fn builtin_export(&mut self, args: &[String]) -> RunResult {
+        if args.is_empty() {
+            let mut exported = self.vars.iter().filter(|(_, var)| var.exported).collect::<Vec<_>>();
+            exported.sort_by_key(|(name, _)| name.as_str());
+            for (name, var) in exported {
+                println!("export {name}='{}'", var.value.replace('\'', "'\\''"));
+            }
+            return Ok(0);
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                eprintln!("{}: export: `{arg}': not a valid identifier", self.name);
+                status = 1;
+                continue;
+            }
+
+            if let Some(value) = value {
+                self.set_var(name, value.to_string());
+            }
+            self.export_var(name);
+        }
+        Ok(status)
+    }
+
+    /// `local NAME[=value]...`, for variables that only last until the function returns.
+    fn builtin_local(&mut self, args: &[String]) -> RunResult {
+        if self.local_frames.is_empty() {
+            eprintln!("{}: local: can only be used in a function", self.name);
+            return Ok(1);
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                eprintln!("{}: local: `{arg}': not a valid identifier", self.name);
+                status = 1;
+                continue;
+            }
+
+            self.make_local(name);
+            if let Some(value) = value {
+                self.set_var(name, value.to_string());
+            }
+        }
+        Ok(status)
+    }
+
+    /// `return [STATUS]`, from the function that's running.
+    fn builtin_return(&mut self, args: &[String]) -> RunResult {
+        if self.local_frames.is_empty() {
+            eprintln!("{}: return: can only be used in a function", self.name);
+            return Ok(1);
+        }
+
+        match args {
+            [] => Err(Jump::Return(self.last_status)),
+            [status] => match status.parse::<i32>() {
+                Ok(status) => Err(Jump::Return(status & 0xff)),
+                Err(_) => {
+                    eprintln!("{}: return: {status}: numeric argument required", self.name);
+                    Err(Jump::Return(2))
+                }
+            },
+            _ => {
+                eprintln!("{}: return: too many arguments", self.name);
+                Ok(1)
+            }
+        }
+    }
+
+    /// The `shopt` option called `name`.
+    fn shell_option(&mut self, name: &str) -> Option<&mut bool> {
+        let options = &mut self.glob_options;
+        Some(match name {
+            "dotglob" => &mut options.dotglob,
+            "failglob" => &mut options.failglob,
+            "globstar" => &mut options.globstar,
+            "nullglob" => &mut options.nullglob,
+            _ => return None,
+        })
+    }
+
+    /// `shopt -s NAME...` or `shopt -u NAME...` to turn options on or off, or `shopt [-q] [NAME...]`
+    /// to see which are on.
+    fn builtin_shopt(&mut self, args: &[String]) -> RunResult {
+        const NAMES: [&str; 4] = ["dotglob", "failglob", "globstar", "nullglob"];
+
+        let (flag, names) = match args.first().map(String::as_str) {
+            Some(flag @ ("-s" | "-u" | "-q")) => (Some(flag), &args[1..]),
+            Some(flag) if flag.starts_with('-') => {
+                eprintln!("{}: shopt: {flag}: invalid option", self.name);
+                eprintln!("shopt: usage: shopt [-s|-u|-q] [optname ...]");
+                return Ok(2);
+            }
+            _ => (None, args),
+        };
+
+        let mut status = 0;
+        let mut selected = Vec::new();
+        for name in names {
+            match self.shell_option(name) {
+                Some(value) => match flag {
+                    Some("-s") => *value = true,
+                    Some("-u") => *value = false,
+                    _ => selected.push((name.as_str(), *value)),
+                },
+                None => {
+                    eprintln!("{}: shopt: {name}: invalid shell option name", self.name);
+                    status = 1;
+                }
+            }
+        }
+
+        if names.is_empty() {
+            for name in NAMES {
+                let value = *self.shell_option(name).expect("listed options exist");
+                // `-s` and `-u` on their own list the options that are on or off
+                if flag != Some(if value { "-u" } else { "-s" }) {
+                    selected.push((name, value));
+                }
+            }
+        } else if matches!(flag, Some("-s" | "-u")) {
+            return Ok(status);
+        }
+
+        for (name, value) in selected {
+            // Asking about particular options is a question of whether they're all on
+            if !value && !names.is_empty() {
+                status = 1;
+            }
+            if flag != Some("-q") {
+                println!("{name:<15}\t{}", if value { "on" } else { "off" });
+            }
+        }
+        Ok(status)
+    }
+
+    /// `test EXPRESSION`, or `[ EXPRESSION ]`.
+    fn builtin_test(&mut self, name: &str, args: &[String]) -> RunResult {
+        let args = match args {
+            [args @ .., last] if name == "[" && last == "]" => args,
+            _ if name == "[" => {
+                eprintln!("{}: [: missing `]'", self.name);
+                return Ok(2);
+            }
+            args => args,
+        };
+
+        match cond::test(args) {
+            Ok(result) => Ok(if result { 0 } else { 1 }),
+            Err(message) => {
+                eprintln!("{}: {name}: {message}", self.name);
+                Ok(2)
+            }
+        }
+    }
+
+    fn builtin_unset(&mut self, args: &[String]) -> RunResult {
+        for name in args {
+            self.unset_var(name);
+        }
+        Ok(0)
+    }
+}
+
+/// Hooks up a freshly forked pipeline stage to the pipes on either side of it.
+fn connect_pipes(read_from: Option<RawFd>, pipe_out: Option<&(Pipe, &RedirType)>) -> io::Result<()> {
+    use RedirType as RT;
+
+    if let Some(read_from) = read_from {
+        dup2(read_from, libc::STDIN_FILENO)?;
+        close(read_from)?;
+    }
+
+    if let Some((pipe, pipe_type)) = pipe_out {
+        close(pipe.read_fd)?;
+        match pipe_type {
+            RT::Stdout => dup2(pipe.write_fd, libc::STDOUT_FILENO)?,
+            RT::Stderr => dup2(pipe.write_fd, libc::STDERR_FILENO)?,
+            RT::Both => {
+                dup2(pipe.write_fd, libc::STDOUT_FILENO)?;
+                dup2(pipe.write_fd, libc::STDERR_FILENO)?;
+            }
+            RT::HereDoc | RT::HereString => unreachable!("pipes only carry output"),
+        }
+        close(pipe.write_fd)?;
+    }
+
+    Ok(())
+}
+
+/// Makes `text` what's read from stdin, by way of a temporary file.
+///
+/// The file is removed straight away, so it's gone once nothing has it open. Unlike a pipe,
+/// it can't fill up before the command gets around to reading it.
+fn feed_stdin(text: &str) -> io::Result<()> {
+    let mut attempt = 0;
+    let mut file = loop {
+        let path = env::temp_dir().join(format!("sig-systems-shell-{}-{attempt}", getpid()));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => {
+                fs::remove_file(&path)?;
+                break file;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    };
+
+    file.write_all(text.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    dup2(file.as_raw_fd(), libc::STDIN_FILENO)
+}
+
+/// Waits for all of `pids` to finish, returning the exit status of the last one.
+fn wait_for(pids: &[pid_t]) -> io::Result<i32> {
+    let mut remaining = pids.to_vec();
+    let mut last_status = 0;
+
+    while !remaining.is_empty() {
+        let WaitReturn { pid, status } = wait()?;
+        remaining.retain(|&waiting_on| waiting_on != pid);
+        if pids.last() == Some(&pid) {
+            last_status = status.exit_code();
+        }
+    }
+
+    Ok(last_status)
+}
@@ -1,17 +1,58 @@
 // HACK: This test suite was written by an LLM
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use std::path::PathBuf;
+    use std::rc::Rc;
 
-    
-    
+    use crate::lexer::{Lexer, Span, Token, WordPart};
     use crate::parser::*;
 
+    use super::control_flow::simple;
+
     fn parse_command(input: &str) -> Option<Command> {
         Command::parse(input).ok()
     }
 
+    /// Like `assert_eq!`, but for syntax trees, where it doesn't matter which parts of the
+    /// input each command was parsed from.
+    macro_rules! assert_tree_eq {
+        ($left:expr, $right:expr $(, $($arg:tt)+)?) => {
+            assert_eq!(without_spans(&$left), without_spans(&$right) $(, $($arg)+)?)
+        };
+    }
+
+    /// `value`'s debug output with the commands' spans left out.
+    fn without_spans(value: &impl std::fmt::Debug) -> String {
+        let debug = format!("{value:?}");
+        let mut stripped = String::new();
+        let mut rest = debug.as_str();
+        while let Some(start) = rest.find(", span: Span {") {
+            stripped.push_str(&rest[..start]);
+            let end = start + rest[start..].find('}').expect("Unclosed span") + 1;
+            rest = &rest[end..];
+        }
+        stripped.push_str(rest);
+        stripped
+    }
+
+    /// Parses a hand-made token stream, for input the lexer would never produce.
+    fn parse_tokens(tokens: Vec<Token>) -> Result<Command, Vec<ParseError>> {
+        Parser::new(tokens.into_iter().map(|token| Ok((token, Span::default()))))
+            .parse_command()
+            .map_err(|errors| errors.into_iter().collect())
+    }
+
+    /// An argument of quoted text.
+    fn quoted(text: &str) -> Arg {
+        Arg::Quoted(vec![Arg::Word(text.to_string())])
+    }
+
+    /// A word token of plain text.
+    fn word(text: &str) -> Token {
+        Token::Word(vec![WordPart::Literal(text.to_string())])
+    }
+
     #[test]
     fn test_word_parsing() {
         let input = "echo hello";
@@ -31,7 +72,7 @@ mod tests {
         let input = "echo $(ls -l)";
         let command = parse_command(input).expect("Failed to parse command");
 
-        assert_eq!(
+        assert_tree_eq!(
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
@@ -40,6 +81,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             ]
         );
@@ -54,7 +96,7 @@ mod tests {
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
-                Arg::Variable("HOME".to_string())
+                Arg::Variable("HOME".into())
             ]
         );
     }
@@ -76,7 +118,7 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Stdout,
-                target: PathBuf::from("output.txt")
+                target: Arg::Word("output.txt".to_string())
             }]
         );
     }
@@ -98,7 +140,7 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Stderr,
-                target: PathBuf::from("error.txt")
+                target: Arg::Word("error.txt".to_string())
             }]
         );
     }
@@ -120,7 +162,7 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Both,
-                target: PathBuf::from("output.txt")
+                target: Arg::Word("output.txt".to_string())
             }]
         );
     }
@@ -138,7 +180,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.pipe_to,
             Some(PipeTo {
                 pipe_type: RedirType::Stdout,
@@ -150,6 +192,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             })
         );
@@ -168,7 +211,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.pipe_to,
             Some(PipeTo {
                 pipe_type: RedirType::Both,
@@ -180,6 +223,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             })
         );
@@ -198,7 +242,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.and_then,
             Some(AndThen {
                 target: Box::new(Command {
@@ -209,6 +253,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 }),
                 conditional: true
             })
@@ -228,7 +273,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.and_then,
             Some(AndThen {
                 target: Box::new(Command {
@@ -239,6 +284,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 }),
                 conditional: false
             })
@@ -277,11 +323,11 @@ mod tests {
             vec![
                 FileRedir {
                     redirect_type: RedirType::Stdout,
-                    target: PathBuf::from("out.txt")
+                    target: Arg::Word("out.txt".to_string())
                 },
                 FileRedir {
                     redirect_type: RedirType::Stderr,
-                    target: PathBuf::from("err.txt")
+                    target: Arg::Word("err.txt".to_string())
                 }
             ]
         );
@@ -296,16 +342,26 @@ mod tests {
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
-                Arg::Variable("HOME_VAR".to_string())
+                Arg::Variable("HOME_VAR".into())
             ]
         );
     }
 
     #[test]
-    fn test_invalid_variable() {
-        let input = "echo $1invalidVar";
-        let command = parse_command(input);
-        assert_eq!(command, None);
+    fn test_positional_then_text() {
+        let input = "echo $1invalidVar ${10} $ a$";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Concat(vec![Arg::Variable("1".into()), Arg::Word("invalidVar".to_string())]),
+                Arg::Variable("10".into()),
+                Arg::Word("$".to_string()),
+                Arg::Word("a$".to_string())
+            ]
+        );
     }
 
     #[test]
@@ -351,11 +407,11 @@ mod tests {
             vec![
                 FileRedir {
                     redirect_type: RedirType::Stdout,
-                    target: PathBuf::from("output.txt")
+                    target: Arg::Word("output.txt".to_string())
                 },
                 FileRedir {
                     redirect_type: RedirType::Stdout,
-                    target: PathBuf::from("another_output.txt")
+                    target: Arg::Word("another_output.txt".to_string())
                 }
             ]
         );
@@ -378,11 +434,11 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Stdout,
-                target: PathBuf::from("output.txt")
+                target: Arg::Word("output.txt".to_string())
             }]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.pipe_to,
             Some(PipeTo {
                 pipe_type: RedirType::Stdout,
@@ -394,6 +450,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             })
         );
@@ -416,11 +473,11 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Stdout,
-                target: PathBuf::from("output.txt")
+                target: Arg::Word("output.txt".to_string())
             }]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.and_then,
             Some(AndThen {
                 target: Box::new(Command {
@@ -431,6 +488,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 }),
                 conditional: true
             })
@@ -442,18 +500,19 @@ mod tests {
         let input = "echo $(echo $USER)";
         let command = parse_command(input).expect("Failed to parse command");
 
-        assert_eq!(
+        assert_tree_eq!(
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
                 Arg::Subshell(Command {
                     argv: vec![
                         Arg::Word("echo".to_string()),
-                        Arg::Variable("USER".to_string())
+                        Arg::Variable("USER".into())
                     ],
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             ]
         );
@@ -464,7 +523,7 @@ mod tests {
         let input = "echo $(ls) > output.txt";
         let command = parse_command(input).expect("Failed to parse command");
 
-        assert_eq!(
+        assert_tree_eq!(
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
@@ -473,6 +532,7 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             ]
         );
@@ -481,7 +541,7 @@ mod tests {
             command.redirect_to,
             vec![FileRedir {
                 redirect_type: RedirType::Stdout,
-                target: PathBuf::from("output.txt")
+                target: Arg::Word("output.txt".to_string())
             }]
         );
     }
@@ -513,7 +573,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.and_then,
             Some(AndThen {
                 target: Box::new(Command {
@@ -531,10 +591,12 @@ mod tests {
                             pipe_to: None,
                             redirect_to: Vec::new(),
                             and_then: None,
+                            ..Default::default()
                         })
                     }),
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 }),
                 conditional: true
             })
@@ -554,7 +616,7 @@ mod tests {
             ]
         );
 
-        assert_eq!(
+        assert_tree_eq!(
             command.pipe_to,
             Some(PipeTo {
                 pipe_type: RedirType::Stdout,
@@ -570,10 +632,12 @@ mod tests {
                             pipe_to: None,
                             redirect_to: Vec::new(),
                             and_then: None,
+                            ..Default::default()
                         })
                     }),
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
                 })
             })
         );
@@ -584,7 +648,7 @@ mod tests {
         let input = "echo $(echo)";
         let command = parse_command(input).expect("Failed to parse command");
 
-        assert_eq!(
+        assert_tree_eq!(
             command.argv,
             vec![
                 Arg::Word("echo".to_string()),
@@ -593,8 +657,1583 @@ mod tests {
                     pipe_to: None,
                     redirect_to: Vec::new(),
                     and_then: None,
+                    ..Default::default()
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        let errors = Command::parse("echo \"hello").expect_err("Parsed an unclosed quote");
+        assert_eq!(
+            errors.to_string(),
+            "syntax error: unterminated string literal\necho \"hello\n     ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_on_later_line() {
+        let errors = Command::parse("echo hi\necho $(ls").expect_err("Parsed an unclosed subshell");
+        assert_eq!(
+            errors.to_string(),
+            "syntax error on line 2: unmatched '('\necho $(ls\n     ^^"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_inside_subshell() {
+        let errors = Command::parse("echo $(echo \"x)").expect_err("Parsed an unclosed quote");
+        assert_eq!(
+            errors.to_string(),
+            "syntax error: unterminated string literal\necho $(echo \"x)\n            ^"
+        );
+    }
+
+    #[test]
+    fn test_command_spans() {
+        let command = parse_command("echo hello > out.txt | grep x").expect("Failed to parse command");
+        assert_eq!(command.span, Span::new(0, 20));
+
+        let target = command.pipe_to.expect("Missing pipe").target;
+        assert_eq!(target.span, Span::new(23, 29));
+
+        let command = Command::parse("echo a;  b").expect("Failed to parse command");
+        assert_eq!(command.and_then.expect("Missing command").target.span, Span::new(9, 10));
+    }
+
+    #[test]
+    fn test_empty_input_error() {
+        let errors = Command::parse("").expect_err("Parsed empty input");
+        assert_eq!(errors.to_string(), "syntax error: empty command");
+    }
+
+    #[test]
+    fn test_tokens_leading_pipe() {
+        let errors = parse_tokens(vec![Token::Pipe, word("grep")])
+            .expect_err("Parsed a pipe with nothing before it");
+        assert!(matches!(errors.as_slice(), [ParseError::MissingCommand(_)]));
+    }
+
+    #[test]
+    fn test_tokens_trailing_operators() {
+        for operator in [Token::Pipe, Token::PipeBoth, Token::AndThenIf] {
+            let errors = parse_tokens(vec![word("echo"), operator])
+                .expect_err("Parsed an operator with nothing after it");
+            assert!(matches!(errors.as_slice(), [ParseError::UnexpectedEof(_)]));
+        }
+    }
+
+    #[test]
+    fn test_tokens_trailing_semicolon() {
+        let command = parse_tokens(vec![word("echo"), Token::AndThen])
+            .expect("Failed to parse command");
+        assert_eq!(command.argv, vec![Arg::Word("echo".to_string())]);
+        assert_eq!(command.and_then, None);
+    }
+
+    #[test]
+    fn test_tokens_redirect_to_operator() {
+        let errors = parse_tokens(vec![
+            word("echo"),
+            Token::RedirOut,
+            Token::Pipe,
+            word("cat"),
+        ])
+        .expect_err("Parsed a redirection without a file name");
+        assert!(matches!(errors.as_slice(), [ParseError::MissingFileName(_)]));
+    }
+
+    #[test]
+    fn test_tokens_only_redirections() {
+        let errors = parse_tokens(vec![Token::RedirErr, Token::RedirBoth])
+            .expect_err("Parsed redirections with no file names or command");
+        assert!(matches!(
+            errors.as_slice(),
+            [ParseError::MissingFileName(_), ParseError::MissingFileName(_)]
+        ));
+    }
+
+    #[test]
+    fn test_lines_run_in_order() {
+        let command = parse_command("echo hello\n\necho world;\n").expect("Failed to parse command");
+
+        assert_tree_eq!(
+            command.and_then,
+            Some(AndThen {
+                target: Box::new(Command {
+                    argv: vec![
+                        Arg::Word("echo".to_string()),
+                        Arg::Word("world".to_string())
+                    ],
+                    pipe_to: None,
+                    redirect_to: Vec::new(),
+                    and_then: None,
+                    ..Default::default()
+                }),
+                conditional: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_and_then_if_continues_on_next_line() {
+        let input = "echo hello &&\n\n  echo world";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert!(command.and_then.is_some_and(|and_then| and_then.conditional));
+    }
+
+    #[test]
+    fn test_parser_stops_at_newline() {
+        let mut parser = Parser::new(Lexer::new("echo a | cat\n\necho b\n"));
+
+        let first = parser.parse_command().expect("Failed to parse first line");
+        assert!(first.pipe_to.is_some());
+        assert!(!parser.at_end());
+
+        let second = parser.parse_command().expect("Failed to parse second line");
+        assert_eq!(second.argv, vec![Arg::Word("echo".to_string()), Arg::Word("b".to_string())]);
+        assert!(parser.at_end());
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        let input = "echo hel\\\nlo \\\n  world \"a\\\nb\"";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Word("hello".to_string()),
+                Arg::Word("world".to_string()),
+                quoted("ab")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_input() {
+        for input in ["echo \"abc", "echo 'abc\n", "echo $(ls", "echo a |", "echo a &&\n", "echo a \\", "echo a\\\n"] {
+            let errors = Command::parse(input).expect_err("Parsed incomplete input");
+            assert!(errors.is_incomplete(), "{input:?} should be incomplete");
+        }
+    }
+
+    #[test]
+    fn test_invalid_input_is_not_incomplete() {
+        for input in ["| echo a", "echo a > ; echo b", "echo a ;; echo b", "fi; if true; then", "| echo \"abc"] {
+            let errors = Command::parse(input).expect_err("Parsed invalid input");
+            assert!(!errors.is_incomplete(), "{input:?} shouldn't be incomplete");
+        }
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "#!/bin/sh\n# A comment\necho hello # world\n";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Word("hello".to_string())
+            ]
+        );
+        assert_eq!(command.and_then, None);
+    }
+
+    #[test]
+    fn test_hash_inside_words() {
+        let input = "echo a#b '#c' \"#d\";# e";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Word("a#b".to_string()),
+                quoted("#c"),
+                quoted("#d")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_comments() {
+        let errors = Command::parse("  # nothing to see here\n").expect_err("Parsed a comment");
+        assert!(errors.is_empty_input());
+    }
+
+    #[test]
+    fn test_backslash_outside_quotes() {
+        let input = r"echo a\ b \'c\' \$HOME x\;y \| \\";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Concat(vec![Arg::Word("a".to_string()), quoted(" "), Arg::Word("b".to_string())]),
+                Arg::Concat(vec![quoted("'"), Arg::Word("c".to_string()), quoted("'")]),
+                Arg::Concat(vec![quoted("$"), Arg::Word("HOME".to_string())]),
+                Arg::Concat(vec![Arg::Word("x".to_string()), quoted(";"), Arg::Word("y".to_string())]),
+                quoted("|"),
+                quoted("\\")
+            ]
+        );
+        assert_eq!(command.pipe_to, None);
+        assert_eq!(command.and_then, None);
+    }
+
+    #[test]
+    fn test_backslash_inside_quotes() {
+        let input = r#"echo "say \"hi\"" "\$ \` \\ \a" '\"'"#;
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                quoted("say \"hi\""),
+                quoted("$ ` \\ \\a"),
+                quoted("\\\"")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaped_parens_in_subshell() {
+        let input = r"echo $(echo \) \()";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        let inner = Command {
+            argv: vec![
+                Arg::Word("echo".to_string()),
+                quoted(")"),
+                quoted("("),
+            ],
+            ..Default::default()
+        };
+        assert_tree_eq!(
+            command.argv,
+            vec![Arg::Word("echo".to_string()), Arg::Subshell(inner)]
+        );
+    }
+
+    #[test]
+    fn test_expansions_inside_words() {
+        let input = "echo \"$HOME/bin\" prefix$VAR '$HOME' \"$(echo hi)\"";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_tree_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Quoted(vec![
+                    Arg::Variable("HOME".into()),
+                    Arg::Word("/bin".to_string())
+                ]),
+                Arg::Concat(vec![
+                    Arg::Word("prefix".to_string()),
+                    Arg::Variable("VAR".into())
+                ]),
+                quoted("$HOME"),
+                Arg::Quoted(vec![Arg::Subshell(Command {
+                    argv: vec![Arg::Word("echo".to_string()), Arg::Word("hi".to_string())],
+                    ..Default::default()
+                })])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_and_mixed_quotes() {
+        let input = "echo \"\" a\"b\"'c'd";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Quoted(Vec::new()),
+                Arg::Concat(vec![
+                    Arg::Word("a".to_string()),
+                    quoted("bc"),
+                    Arg::Word("d".to_string())
+                ])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expanded_redirection_target() {
+        let command = parse_command("echo hi > \"$DIR\"/out.txt").expect("Failed to parse command");
+
+        assert_eq!(
+            command.redirect_to,
+            vec![FileRedir {
+                redirect_type: RedirType::Stdout,
+                target: Arg::Concat(vec![
+                    Arg::Quoted(vec![Arg::Variable("DIR".into())]),
+                    Arg::Word("/out.txt".to_string())
+                ])
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_in_word_subshell() {
+        let errors = Command::parse("echo x$(echo \"y)z").expect_err("Parsed an unclosed quote");
+        assert_eq!(
+            errors.to_string(),
+            "syntax error: unterminated string literal\necho x$(echo \"y)z\n             ^"
+        );
+    }
+
+    #[test]
+    fn test_ansi_c_quoting() {
+        let input = r"echo $'a\tb\n' $'\x41é\0101\101' $'it\'s' $'ab\0cd' $'\q'";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                quoted("a\tb\n"),
+                quoted("AéAA"),
+                quoted("it's"),
+                quoted("ab"),
+                quoted("\\q")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locale_string() {
+        let input = "echo $\"hi $USER\"x";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Concat(vec![
+                    Arg::Quoted(vec![
+                        Arg::Word("hi ".to_string()),
+                        Arg::Variable("USER".into())
+                    ]),
+                    Arg::Word("x".to_string())
+                ])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_ansi_c_quote() {
+        let errors = Command::parse("echo $'abc\\'").expect_err("Parsed an unclosed quote");
+        assert!(errors.is_incomplete());
+        assert_eq!(
+            errors.to_string(),
+            "syntax error: unterminated string literal\necho $'abc\\'\n     ^"
+        );
+    }
+
+    #[test]
+    fn test_braced_parameters() {
+        let input = "echo ${HOME}/x ${#x} ${x:-a b} ${x=$y}";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Concat(vec![Arg::Variable("HOME".into()), Arg::Word("/x".to_string())]),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::Length)
+                }),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::Missing {
+                        action: MissingAction::Default,
+                        colon: true,
+                        word: Box::new(Arg::Word("a b".to_string()))
+                    })
+                }),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::Missing {
+                        action: MissingAction::Assign,
+                        colon: false,
+                        word: Box::new(Arg::Variable("y".into()))
+                    })
                 })
             ]
         );
     }
+
+    #[test]
+    fn test_pattern_parameters() {
+        let input = "echo ${x##*/} ${x%\".rs\"} ${x//a/}";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::TrimPrefix {
+                        pattern: Box::new(Arg::Word("*/".to_string())),
+                        longest: true
+                    })
+                }),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::TrimSuffix {
+                        pattern: Box::new(quoted(".rs")),
+                        longest: false
+                    })
+                }),
+                Arg::Variable(Param {
+                    name: "x".to_string(),
+                    op: Some(ParamOp::Replace {
+                        pattern: Box::new(Arg::Word("a".to_string())),
+                        replacement: Box::new(Arg::Concat(Vec::new())),
+                        mode: ReplaceMode::All
+                    })
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_substitution() {
+        let errors = Command::parse("echo ${x!} ${}").expect_err("Parsed a bad substitution");
+        assert_eq!(
+            errors.to_string(),
+            "syntax error: bad substitution\necho ${x!} ${}\n     ^^^^^\nsyntax error: bad substitution\necho ${x!} ${}\n           ^^^"
+        );
+
+        let errors = Command::parse("echo ${x:-").expect_err("Parsed an unclosed brace");
+        assert!(errors.is_incomplete());
+    }
+
+    #[test]
+    fn test_special_parameters() {
+        let input = "echo $$ $! $# $@ \"$*\" $? $0 $12 ${#} ${#@} ${##}";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        let length = |name: &str| {
+            Arg::Variable(Param {
+                name: name.to_string(),
+                op: Some(ParamOp::Length),
+            })
+        };
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Variable("$".into()),
+                Arg::Variable("!".into()),
+                Arg::Variable("#".into()),
+                Arg::Variable("@".into()),
+                Arg::Quoted(vec![Arg::Variable("*".into())]),
+                Arg::Variable("?".into()),
+                Arg::Variable("0".into()),
+                Arg::Concat(vec![Arg::Variable("1".into()), Arg::Word("2".to_string())]),
+                Arg::Variable("#".into()),
+                length("@"),
+                length("#")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assignments() {
+        let input = "A=1 B=\"$x\"y env C=2";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.assignments,
+            vec![
+                Assignment {
+                    name: "A".to_string(),
+                    value: Arg::Word("1".to_string())
+                },
+                Assignment {
+                    name: "B".to_string(),
+                    value: Arg::Concat(vec![
+                        Arg::Quoted(vec![Arg::Variable("x".into())]),
+                        Arg::Word("y".to_string())
+                    ])
+                }
+            ]
+        );
+        assert_eq!(
+            command.argv,
+            vec![Arg::Word("env".to_string()), Arg::Word("C=2".to_string())]
+        );
+
+        let command = parse_command("x= '1y=2'").expect("Failed to parse command");
+        assert_eq!(command.assignments.len(), 1);
+        assert_eq!(command.argv, vec![quoted("1y=2")]);
+    }
+
+    #[test]
+    fn test_tilde_parsing() {
+        let tilde = |prefix: &str| Arg::Tilde(prefix.to_string());
+        let command = parse_command("ls ~ ~/src ~user/x ~+ a~ \\~ ~\"user\" ~$x > ~/out").expect("Failed to parse command");
+
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("ls".to_string()),
+                tilde(""),
+                Arg::Concat(vec![tilde(""), Arg::Word("/src".to_string())]),
+                Arg::Concat(vec![tilde("user"), Arg::Word("/x".to_string())]),
+                tilde("+"),
+                Arg::Word("a~".to_string()),
+                quoted("~"),
+                Arg::Concat(vec![Arg::Word("~".to_string()), quoted("user")]),
+                Arg::Concat(vec![Arg::Word("~".to_string()), Arg::Variable("x".into())])
+            ]
+        );
+        assert_eq!(
+            command.redirect_to[0].target,
+            Arg::Concat(vec![tilde(""), Arg::Word("/out".to_string())])
+        );
+
+        let command = parse_command("PATH=~/bin:$PATH:~-:a~ echo x=~").expect("Failed to parse command");
+        assert_eq!(
+            command.assignments[0].value,
+            Arg::Concat(vec![
+                tilde(""),
+                Arg::Word("/bin:".to_string()),
+                Arg::Variable("PATH".into()),
+                Arg::Word(":".to_string()),
+                tilde("-"),
+                Arg::Word(":a~".to_string())
+            ])
+        );
+        assert_eq!(command.argv[1], Arg::Word("x=~".to_string()));
+    }
+
+    #[test]
+    fn test_heredoc_parsing() {
+        let input = "cat <<EOF <<-'END'; echo next\nhi $x\nEOF\n\t\tlit $x\n\tEND\necho last";
+        let command = parse_command(input).expect("Failed to parse command");
+
+        assert_eq!(
+            command.redirect_to,
+            vec![
+                FileRedir {
+                    redirect_type: RedirType::HereDoc,
+                    target: Arg::Quoted(vec![
+                        Arg::Word("hi ".to_string()),
+                        Arg::Variable("x".into()),
+                        Arg::Word("\n".to_string())
+                    ])
+                },
+                FileRedir {
+                    redirect_type: RedirType::HereDoc,
+                    target: quoted("lit $x\n")
+                }
+            ]
+        );
+
+        // The bodies are skipped over once the line ends
+        let next = command.and_then.expect("No command after the first");
+        assert_eq!(next.target.argv, vec![Arg::Word("echo".to_string()), Arg::Word("next".to_string())]);
+        let last = next.target.and_then.expect("No command after the bodies");
+        assert_eq!(last.target.argv, vec![Arg::Word("echo".to_string()), Arg::Word("last".to_string())]);
+
+        let command = parse_command("cat<<\\EOF\n\\$x\nEOF").expect("Failed to parse command");
+        assert_eq!(command.argv, vec![Arg::Word("cat".to_string())]);
+        assert_eq!(command.redirect_to[0].target, quoted("\\$x\n"));
+
+        let errors = Command::parse("cat <<EOF\nbody").expect_err("Parsed an unfinished here-document");
+        assert!(errors.is_incomplete());
+    }
+
+    #[test]
+    fn test_here_string_parsing() {
+        let command = parse_command("grep foo <<< \"$text\" <<<~").expect("Failed to parse command");
+
+        assert_eq!(
+            command.redirect_to,
+            vec![
+                FileRedir {
+                    redirect_type: RedirType::HereString,
+                    target: Arg::Quoted(vec![Arg::Variable("text".into())])
+                },
+                FileRedir {
+                    redirect_type: RedirType::HereString,
+                    target: Arg::Tilde(String::new())
+                }
+            ]
+        );
+
+        let errors = Command::parse("cat <<<").expect_err("Parsed a here-string with no word");
+        assert!(matches!(errors.into_iter().collect::<Vec<_>>()[..], [ParseError::UnexpectedToken(_)]));
+    }
+
+    #[test]
+    fn test_process_substitution_parsing() {
+        let command = parse_command("diff <(sort a) >(cat)>out").expect("Failed to parse command");
+        let sort = Command {
+            argv: vec![Arg::Word("sort".to_string()), Arg::Word("a".to_string())],
+            ..Default::default()
+        };
+        let cat = Command {
+            argv: vec![Arg::Word("cat".to_string())],
+            ..Default::default()
+        };
+
+        assert_tree_eq!(
+            command.argv,
+            vec![
+                Arg::Word("diff".to_string()),
+                Arg::ProcSub { command: sort, output: false },
+                Arg::ProcSub { command: cat, output: true }
+            ]
+        );
+        assert_eq!(command.redirect_to.len(), 1);
+
+        let errors = Command::parse("cat <(echo").expect_err("Parsed an unclosed process substitution");
+        assert!(errors.is_incomplete());
+    }
+
+    #[test]
+    fn test_if_parsing() {
+        let command = parse_command("if a; then b; elif c\nthen d\nelse e; f; fi > out; g").expect("Failed to parse command");
+        let mut else_list = simple("e");
+        else_list.and_then = Some(AndThen {
+            conditional: false,
+            target: Box::new(simple("f")),
+        });
+
+        assert_tree_eq!(
+            command.compound,
+            Some(Compound::If {
+                branches: vec![(simple("a"), simple("b")), (simple("c"), simple("d"))],
+                otherwise: Some(Box::new(else_list))
+            })
+        );
+        assert!(command.argv.is_empty());
+        assert_eq!(command.redirect_to.len(), 1);
+        assert_eq!(command.and_then.map(|and_then| and_then.target.argv), Some(vec![Arg::Word("g".to_string())]));
+
+        // Reserved words only count where a command's name would go, and unquoted
+        let command = parse_command("echo if then \"fi\"").expect("Failed to parse command");
+        assert_eq!(command.argv.len(), 4);
+    }
+
+    #[test]
+    fn test_if_parse_errors() {
+        for input in ["then", "fi", "if true; fi", "if true; then fi", "if true; then echo; fi x", "if then; fi"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["if true", "if true; then", "if true; then echo\nelse"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_while_parsing() {
+        let command = parse_command("until a; do b; done; c").expect("Failed to parse command");
+
+        assert_tree_eq!(
+            command.compound,
+            Some(Compound::While {
+                until: true,
+                condition: Box::new(simple("a")),
+                body: Box::new(simple("b"))
+            })
+        );
+        assert_eq!(command.and_then.map(|and_then| and_then.target.argv), Some(vec![Arg::Word("c".to_string())]));
+
+        for input in ["do", "done", "while true; done", "while true; do done", "while; do echo; done"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["while true", "while true; do", "until true\ndo echo\n"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_for_parsing() {
+        let command = parse_command("for x in a{b,c} \"$y\"\ndo\n  z\ndone").expect("Failed to parse command");
+
+        assert_tree_eq!(
+            command.compound,
+            Some(Compound::For {
+                name: "x".to_string(),
+                words: Some(vec![
+                    Arg::Word("ab".to_string()),
+                    Arg::Word("ac".to_string()),
+                    Arg::Quoted(vec![Arg::Variable("y".into())])
+                ]),
+                body: Box::new(simple("z"))
+            })
+        );
+
+        // No `in` means the positional parameters, which is different from no words
+        for input in ["for x; do z; done", "for x do z; done", "for x\ndo z; done"] {
+            let command = parse_command(input).expect(input);
+            assert!(matches!(command.compound, Some(Compound::For { words: None, .. })), "{input}");
+        }
+        let command = parse_command("for x in; do z; done").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::For { words: Some(words), .. }) if words.is_empty()));
+
+        let command = parse_command("for ((i = 0; ; i++)) do z; done").expect("Failed to parse command");
+        assert_tree_eq!(
+            command.compound,
+            Some(Compound::ArithFor {
+                init: Box::new(Arg::Word("i = 0".to_string())),
+                condition: None,
+                step: Box::new(Arg::Word(" i++".to_string())),
+                body: Box::new(simple("z"))
+            })
+        );
+
+        for input in ["for; do z; done", "for 1x in a; do z; done", "for x in a do z; done", "for ((i; i)); do z; done"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["for", "for x in a b", "for x in a b;", "for ((;;))"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_case_parsing() {
+        let command = parse_command("case $x in\n  a | \"b\") y;;\n  (c) ;&\n  *)\n    z;;&\nesac > out").expect("Failed to parse command");
+
+        assert_tree_eq!(
+            command.compound,
+            Some(Compound::Case {
+                word: Box::new(Arg::Variable("x".into())),
+                arms: vec![
+                    CaseArm {
+                        patterns: vec![Arg::Word("a".to_string()), Arg::Quoted(vec![Arg::Word("b".to_string())])],
+                        body: Some(simple("y")),
+                        terminator: CaseTerminator::Break
+                    },
+                    CaseArm {
+                        patterns: vec![Arg::Word("c".to_string())],
+                        body: None,
+                        terminator: CaseTerminator::FallThrough
+                    },
+                    CaseArm {
+                        patterns: vec![Arg::Word("*".to_string())],
+                        body: Some(simple("z")),
+                        terminator: CaseTerminator::Continue
+                    },
+                ]
+            })
+        );
+        assert_eq!(command.redirect_to.len(), 1);
+
+        // The last arm doesn't need its `;;`, and there needn't be any arms at all
+        let command = parse_command("case x in x) y; esac").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::Case { arms, .. }) if arms.len() == 1));
+        let command = parse_command("case x in esac").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::Case { arms, .. }) if arms.is_empty()));
+
+        for input in ["esac", "case x; esac", "case x in x y) z;; esac", "case x in x|) y;; esac", "case x in () y;; esac", "echo x)", "echo x;; echo y"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["case", "case x", "case x in", "case x in x", "case x in x) y;;"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_function_parsing() {
+        let group = |name: &str| Command {
+            compound: Some(Compound::Group(Box::new(simple(name)))),
+            ..Default::default()
+        };
+
+        for input in ["f() { x; }", "f () {\n  x\n}", "function f { x; }", "function f()\n{ x; }"] {
+            let command = parse_command(input).expect(input);
+            assert_tree_eq!(
+                command.compound,
+                Some(Compound::Function { name: "f".to_string(), body: Rc::new(group("x")) }),
+                "{input}"
+            );
+        }
+
+        let command = parse_command("f() if x; then y; fi; f").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::Function { body, .. }) if matches!(body.compound, Some(Compound::If { .. }))));
+        assert!(command.and_then.is_some());
+
+        let command = parse_command("{ x; y; } > out").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::Group(_))));
+        assert_eq!(command.redirect_to.len(), 1);
+
+        for input in ["}", "{ }", "f() x", "f() { x; } y", "x f() { y; }", "function { x; }"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["f()", "f() {", "{ x", "{ x }", "function f"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_conditional_parsing() {
+        let word = |text: &str| Arg::Word(text.to_string());
+        let cond = |input: &str| match parse_command(input).map(|command| command.compound) {
+            Some(Some(Compound::Conditional(expr))) => *expr,
+            other => panic!("{input} parsed as {other:?}"),
+        };
+
+        // `&&` binds tighter than `||`, and `!` tighter than both
+        assert_eq!(
+            cond("[[ -f $x || ! a < b && 1 -eq 2 ]]"),
+            CondExpr::Or(
+                Box::new(CondExpr::Unary { op: "-f".to_string(), operand: Arg::Variable("x".into()) }),
+                Box::new(CondExpr::And(
+                    Box::new(CondExpr::Not(Box::new(CondExpr::Binary {
+                        left: word("a"),
+                        op: "<".to_string(),
+                        right: word("b")
+                    }))),
+                    Box::new(CondExpr::Binary { left: word("1"), op: "-eq".to_string(), right: word("2") })
+                ))
+            )
+        );
+
+        assert_eq!(
+            cond("[[ (a || b) && c ]]"),
+            CondExpr::And(
+                Box::new(CondExpr::Or(Box::new(CondExpr::Word(word("a"))), Box::new(CondExpr::Word(word("b"))))),
+                Box::new(CondExpr::Word(word("c")))
+            )
+        );
+
+        // The right of `=~` is a single word, even with parentheses and `|` in it
+        assert_eq!(
+            cond("[[ $x =~ ^(a|b)+$ ]]"),
+            CondExpr::Binary { left: Arg::Variable("x".into()), op: "=~".to_string(), right: word("^(a|b)+$") }
+        );
+
+        let command = parse_command("[[ x ]] && echo y").expect("Failed to parse command");
+        assert!(matches!(command.compound, Some(Compound::Conditional(_))));
+        assert!(command.and_then.is_some());
+
+        for input in ["[[ ]]", "[[ a b ]]", "[[ a | b ]]", "[[ a ]] b"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(!errors.is_incomplete(), "{input}");
+        }
+
+        for input in ["[[", "[[ a", "[[ a &&", "[[ (a"] {
+            let errors = Command::parse(input).expect_err(input);
+            assert!(errors.is_incomplete(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_parsing() {
+        let command = parse_command("echo $((1 + $x))").expect("Failed to parse command");
+        assert_eq!(
+            command.argv,
+            vec![
+                Arg::Word("echo".to_string()),
+                Arg::Arith(Box::new(Arg::Concat(vec![
+                    Arg::Word("1 + ".to_string()),
+                    Arg::Variable("x".into())
+                ])))
+            ]
+        );
+
+        let command = parse_command("((i++)) > out").expect("Failed to parse command");
+        assert_eq!(command.compound, Some(Compound::Arith(Box::new(Arg::Word("i++".to_string())))));
+        assert!(command.argv.is_empty());
+        assert_eq!(command.redirect_to.len(), 1);
+
+        // Not closed by `))`, so it's a subshell after all
+        let command = parse_command("echo $((a) (b))").expect("Failed to parse command");
+        assert!(matches!(command.argv[1], Arg::Subshell(_)));
+
+        let errors = Command::parse("((1)) echo").expect_err("Parsed a word after (( ))");
+        assert_eq!(errors.to_string(), "syntax error: unexpected token\n((1)) echo\n      ^^^^");
+
+        let errors = Command::parse("echo $((1 +").expect_err("Parsed an unclosed $((");
+        assert!(errors.is_incomplete());
+    }
+}
+
+#[cfg(test)]
+mod input {
+    use crate::safe_wrappers::{close, pipe, read_line, read_to_end};
+
+    #[test]
+    fn test_read_line() {
+        let fds = pipe().expect("Failed to make a pipe");
+        let text = b"echo a\ncat\nrest\n";
+        assert_eq!(unsafe { libc::write(fds.write_fd, text.as_ptr().cast(), text.len()) }, text.len() as isize);
+        close(fds.write_fd).unwrap();
+
+        let mut line = Vec::new();
+        assert_eq!(read_line(fds.read_fd, &mut line).unwrap(), 7);
+        assert_eq!(read_line(fds.read_fd, &mut line).unwrap(), 4);
+        assert_eq!(line, b"echo a\ncat\n");
+
+        // The rest is still there for something else to read
+        assert_eq!(read_to_end(fds.read_fd).unwrap(), b"rest\n");
+        assert_eq!(read_line(fds.read_fd, &mut line).unwrap(), 0);
+        close(fds.read_fd).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod expansion {
+    use crate::parser::Command;
+    use crate::shell::Shell;
+
+    /// Expands the words of the (single) command in `input`.
+    fn expand(shell: &mut Shell, input: &str) -> Vec<String> {
+        let command = Command::parse(input).expect("Failed to parse command");
+        shell.expand_words(&command.argv).expect("Failed to expand")
+    }
+
+    fn shell_with_args(args: &[&str]) -> Shell {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        Shell::new("script".to_string(), args)
+    }
+
+    fn shell_with(vars: &[(&str, &str)]) -> Shell {
+        let mut shell = Shell::new("sh".to_string(), Vec::new());
+        for (name, value) in vars {
+            shell.set_var(name, value.to_string());
+        }
+        shell
+    }
+
+    #[test]
+    fn test_missing_parameters() {
+        let mut shell = shell_with(&[("set", "value"), ("empty", "")]);
+        shell.unset_var("unset");
+
+        assert_eq!(
+            expand(
+                &mut shell,
+                "\"${unset-a}\" \"${empty-b}\" ${empty:-c} ${set:-d} ${set:+e} ${empty+f} \"${unset+g}\""
+            ),
+            ["a", "", "c", "value", "e", "f", ""]
+        );
+
+        assert_eq!(expand(&mut shell, "${unset:=new} $unset"), ["new", "new"]);
+        assert_eq!(expand(&mut shell, "${#set} ${#empty}"), ["5", "0"]);
+    }
+
+    #[test]
+    fn test_missing_parameter_error() {
+        let mut shell = shell_with(&[("empty", "")]);
+        let command = Command::parse("${empty:?oops}").expect("Failed to parse command");
+        assert!(shell.expand_words(&command.argv).is_err());
+    }
+
+    #[test]
+    fn test_trimming_parameters() {
+        let mut shell = shell_with(&[("path", "/usr/local/lib.tar.gz"), ("star", "*")]);
+
+        assert_eq!(
+            expand(&mut shell, "${path#*/} ${path##*/} ${path%.*} ${path%%.*} ${path#/usr}"),
+            ["usr/local/lib.tar.gz", "lib.tar.gz", "/usr/local/lib.tar", "/usr/local/lib", "/local/lib.tar.gz"]
+        );
+
+        // Quoted and substituted patterns
+        assert_eq!(expand(&mut shell, "${path#\"*/\"} \"${path##$star}\""), ["/usr/local/lib.tar.gz", ""]);
+    }
+
+    #[test]
+    fn test_replacing_parameters() {
+        let mut shell = shell_with(&[("x", "banana")]);
+
+        assert_eq!(
+            expand(&mut shell, "${x/a/o} ${x//a/o} ${x/#b/B} ${x/%a/A} ${x/#a/A} ${x//an} ${x/n*/[$x]}"),
+            ["bonana", "bonono", "Banana", "bananA", "banana", "ba", "ba[banana]"]
+        );
+    }
+
+    #[test]
+    fn test_positional_parameters() {
+        let mut shell = shell_with_args(&["a b", "", "c"]);
+
+        assert_eq!(expand(&mut shell, "$0 $# \"$1\" [$2] $3 [$4]"), ["script", "3", "a b", "[]", "c", "[]"]);
+        assert_eq!(expand(&mut shell, "\"$@\""), ["a b", "", "c"]);
+        assert_eq!(expand(&mut shell, "x\"<$@>\"y"), ["x<a b", "", "c>y"]);
+        assert_eq!(expand(&mut shell, "\"$*\" ${#@} ${3:-none} ${4:-none}"), ["a b  c", "3", "c", "none"]);
+
+        shell.set_var("IFS", ":".to_string());
+        assert_eq!(expand(&mut shell, "\"$*\""), ["a b::c"]);
+    }
+
+    #[test]
+    fn test_no_positional_parameters() {
+        let mut shell = shell_with_args(&[]);
+
+        assert_eq!(expand(&mut shell, "start \"$@\" $@ $* end"), ["start", "end"]);
+        assert_eq!(expand(&mut shell, "\"$*\""), [""]);
+    }
+
+    #[test]
+    fn test_status_and_pid() {
+        let mut shell = shell_with_args(&[]);
+        shell.last_status = 42;
+
+        assert_eq!(expand(&mut shell, "$? $$ [$!]"), ["42", &shell.pid.to_string(), "[]"]);
+    }
+
+    #[test]
+    fn test_command_substitution_status() {
+        let mut shell = shell_with_args(&[]);
+
+        // With only assignments, the last substitution's status is the command's
+        assert_eq!(shell.run_script("x=$(false)"), 1);
+        assert_eq!(shell.run_script("x=$(exit 3) y=$?; s=$y"), 0);
+        assert_eq!(shell.var("s"), Some("3"));
+        assert_eq!(shell.run_script("x=$(false) y=$(true)"), 0);
+        assert_eq!(shell.run_script("if x=$(false); then s=yes; else s=no; fi"), 0);
+        assert_eq!(shell.var("s"), Some("no"));
+
+        // Otherwise it's the command's own status, and without one it's 0
+        assert_eq!(shell.run_script("true $(false)"), 0);
+        assert_eq!(shell.run_script("false; x=1"), 0);
+    }
+
+    #[test]
+    fn test_field_splitting() {
+        let mut shell = shell_with_args(&["a b", "c"]);
+        shell.set_var("x", "  a  b  ".to_string());
+        shell.set_var("empty", String::new());
+
+        assert_eq!(expand(&mut shell, "$x \"$x\""), ["a", "b", "  a  b  "]);
+        assert_eq!(expand(&mut shell, "[$x] $x$x"), ["[", "a", "b", "]", "a", "b", "a", "b"]);
+        assert_eq!(expand(&mut shell, "$(echo 1 2) $@ [$empty]"), ["1", "2", "a", "b", "c", "[]"]);
+
+        // Other characters make fields even when they're empty, but not at the end
+        shell.set_var("IFS", " :".to_string());
+        shell.set_var("x", ":a::b : c:".to_string());
+        assert_eq!(expand(&mut shell, "$x"), ["", "a", "", "b", "c"]);
+
+        // Only expansions get split
+        assert_eq!(expand(&mut shell, "a:b"), ["a:b"]);
+
+        shell.set_var("IFS", String::new());
+        assert_eq!(expand(&mut shell, "$x"), [":a::b : c:"]);
+    }
+
+    #[test]
+    fn test_heredoc() {
+        let mut shell = shell_with(&[("x", "X")]);
+
+        assert_eq!(
+            expand(&mut shell, "\"$(cat <<EOF\n$x \\$x $((1 + 1)) 'q'\nEOF\n)\""),
+            ["X $x 2 'q'"]
+        );
+        assert_eq!(expand(&mut shell, "\"$(cat <<'EOF'\n$x\nEOF\n)\""), ["$x"]);
+    }
+
+    #[test]
+    fn test_here_string() {
+        let mut shell = shell_with(&[("x", "a  b")]);
+
+        assert_eq!(expand(&mut shell, "\"$(cat <<< $x)\" $(wc -l <<< \"\")"), ["a  b", "1"]);
+    }
+
+    #[test]
+    fn test_process_substitution() {
+        let mut shell = shell_with(&[]);
+
+        assert_eq!(expand(&mut shell, "\"$(cat <(echo one) <(echo two))\""), ["one\ntwo"]);
+
+        let output = expand(&mut shell, "\"$(tee >(tr a-z A-Z) <<< hi)\"");
+        let mut lines = output[0].lines().collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(lines, ["HI", "hi"]);
+
+        // Each substitution's command only holds its own pipe open, or this would never finish
+        let output = expand(&mut shell, "\"$(tee >(tr a-z A-Z) >(tr h H) <<< hi; echo done)\"");
+        let mut lines = output[0].lines().collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(lines, ["HI", "Hi", "done", "hi"]);
+    }
+
+    #[test]
+    fn test_brace_expansion() {
+        let mut shell = shell_with(&[("x", "X"), ("HOME", "/home/me")]);
+
+        assert_eq!(expand(&mut shell, "src/{bin,lib,tests}"), ["src/bin", "src/lib", "src/tests"]);
+        assert_eq!(expand(&mut shell, "{a,{b,c}}{1,2}"), ["a1", "a2", "b1", "b2", "c1", "c2"]);
+        assert_eq!(expand(&mut shell, "{1..4} {3..1}"), ["1", "2", "3", "4", "3", "2", "1"]);
+        assert_eq!(
+            expand(&mut shell, "{1..10..4} {08..11} {-1..1}"),
+            ["1", "5", "9", "08", "09", "10", "11", "-1", "0", "1"]
+        );
+        assert_eq!(expand(&mut shell, "{a..e..2} {C..A}"), ["a", "c", "e", "C", "B", "A"]);
+
+        // Expansions and quotes go along with the text around them
+        assert_eq!(expand(&mut shell, "{$x,\"y z\"}! ~{,/src}"), ["X!", "y z!", "/home/me", "/home/me/src"]);
+
+        // None of these are brace expansions
+        assert_eq!(
+            expand(&mut shell, "{} {a} \"{a,b}\" \\{a,b} {1..x} {1..2..3..4} {a,b"),
+            ["{}", "{a}", "{a,b}", "{a,b}", "{1..x}", "{1..2..3..4}", "{a,b"]
+        );
+        assert_eq!(expand(&mut shell, "{a}{b,c}"), ["{a}b", "{a}c"]);
+    }
+
+    #[test]
+    fn test_tilde_expansion() {
+        let mut shell = shell_with(&[("HOME", "/home/me"), ("PWD", "/here"), ("OLDPWD", "/there")]);
+
+        assert_eq!(
+            expand(&mut shell, "~ ~/src ~+ ~-/x ~no-such-user/x"),
+            ["/home/me", "/home/me/src", "/here", "/there/x", "~no-such-user/x"]
+        );
+
+        // What `~` stands for isn't split or globbed
+        shell.set_var("HOME", "/a b/*".to_string());
+        assert_eq!(expand(&mut shell, "~"), ["/a b/*"]);
+
+        shell.unset_var("HOME");
+        assert_eq!(expand(&mut shell, "~/x"), ["~/x"]);
+    }
+
+    #[test]
+    fn test_cd() {
+        let mut shell = shell_with(&[]);
+        let start = shell.var("PWD").expect("PWD is set").to_string();
+        let dir = std::env::temp_dir().canonicalize().expect("Failed to find the temporary directory");
+        let dir = dir.to_str().expect("The temporary directory isn't UTF-8");
+
+        // `cd` changes directory for the whole process, so it's kept to a subshell where
+        // it can't affect the tests running alongside this one. (What `cd -` prints is
+        // caught by the test harness, so it isn't in the output.)
+        shell.run_script(&format!(
+            "s=$(cd {dir}; echo \"$? $PWD $OLDPWD $(pwd)\"; cd /no/such/dir; echo \"$? $PWD\"; cd -; echo \"$? $PWD\")"
+        ));
+        assert_eq!(shell.var("s"), Some(format!("0 {dir} {start} {dir}\n1 {dir}\n0 {start}").as_str()));
+        assert_eq!(shell.var("PWD"), Some(start.as_str()));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut shell = shell_with(&[("x", "5"), ("expr", "x * 2"), ("empty", "")]);
+
+        assert_eq!(
+            expand(&mut shell, "$((1 + 2 * 3)) $(((1 + 2) * 3)) $((2 ** 3 ** 2)) $((-7 / 2)) $((-7 % 2)) $((1 << 4 | 1))"),
+            ["7", "9", "512", "-3", "-1", "17"]
+        );
+        assert_eq!(
+            expand(&mut shell, "$((0x1f)) $((017)) $((2#101)) $((36#z)) $((x > 3 ? 10 : 20)) $((!x)) $((~0))"),
+            ["31", "15", "5", "35", "10", "0", "-1"]
+        );
+
+        // Variables, with or without `$`, and whose values are expressions themselves
+        assert_eq!(expand(&mut shell, "$((x + $x)) $((expr + 1)) $((empty + unset))"), ["10", "11", "0"]);
+
+        assert_eq!(expand(&mut shell, "$((x += 2)) $x $((x++)) $x $((--x)) $((y = z = 3)) $y$z"), ["7", "7", "7", "8", "7", "3", "33"]);
+
+        // `&&` and `||` don't evaluate what they don't need to
+        assert_eq!(expand(&mut shell, "$((0 && (x = 1))) $((1 || (x = 1))) $x"), ["0", "1", "7"]);
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let mut shell = shell_with(&[("loop", "loop + 1")]);
+
+        for input in ["$((1 / 0))", "$((2 ** -1))", "$((1 +))", "$((08))", "$((loop))", "$((1 = 2))"] {
+            let command = Command::parse(input).expect("Failed to parse command");
+            assert!(shell.expand_words(&command.argv).is_err(), "{input} expanded");
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_command() {
+        let mut shell = shell_with(&[("a", "3")]);
+
+        assert_eq!(shell.run_script("((a > 2))"), 0);
+        assert_eq!(shell.run_script("((a - 3))"), 1);
+        assert_eq!(shell.run_script("((a *= 2))"), 0);
+        assert_eq!(shell.var("a"), Some("6"));
+    }
+}
+
+#[cfg(test)]
+mod patterns {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::glob::{self, GlobOptions, Pattern};
+    use crate::parser::Command;
+    use crate::shell::Shell;
+
+    /// A fresh directory holding `files` (and the directories they're in), for `name`d tests.
+    fn scratch_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shell-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
+            fs::write(path, "").expect("Failed to create file");
+        }
+        dir
+    }
+
+    /// The paths matching `pattern` inside `dir`, relative to it.
+    fn glob_in(dir: &Path, pattern: &str, options: GlobOptions) -> Vec<String> {
+        let prefix = format!("{}/", glob::escape(dir.to_str().unwrap()));
+        glob::glob(&format!("{prefix}{pattern}"), options)
+            .into_iter()
+            .map(|path| path[prefix.len()..].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        let cases = [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "main.rc", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("*a*b*", "xxaxxbxx", true),
+            ("[a-c]x", "bx", true),
+            ("[!a-c]x", "bx", false),
+            ("[]]", "]", true),
+            ("[[:digit:]]*", "1st", true),
+            ("\\*", "*", true),
+            ("\\*", "x", false),
+            ("[abc", "[abc", true),
+        ];
+
+        for (pattern, text, expected) in cases {
+            assert_eq!(Pattern::new(pattern).matches(text), expected, "{pattern} against {text}");
+        }
+    }
+    #[test]
+    fn test_path_expansion() {
+        let dir = scratch_dir("glob", &["b.rs", "a.rs", "c.txt", ".hidden.rs", "src/d.rs", "src/deep/e.rs"]);
+        let options = GlobOptions::default();
+
+        assert_eq!(glob_in(&dir, "*.rs", options), ["a.rs", "b.rs"]);
+        assert_eq!(glob_in(&dir, "[ab].*", options), ["a.rs", "b.rs"]);
+        assert_eq!(glob_in(&dir, ".*", options), [".hidden.rs"]);
+        assert_eq!(glob_in(&dir, "*/*.rs", options), ["src/d.rs"]);
+        assert_eq!(glob_in(&dir, "*/", options), ["src/"]);
+        assert!(glob_in(&dir, "*.none", options).is_empty());
+
+        let options = GlobOptions { dotglob: true, globstar: true, ..options };
+        assert_eq!(glob_in(&dir, "*.rs", options), [".hidden.rs", "a.rs", "b.rs"]);
+        assert_eq!(glob_in(&dir, "**/*.rs", options), [".hidden.rs", "a.rs", "b.rs", "src/d.rs", "src/deep/e.rs"]);
+        assert_eq!(glob_in(&dir, "src/**", options), ["src/", "src/d.rs", "src/deep", "src/deep/e.rs"]);
+
+        fs::remove_dir_all(dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_unmatched_patterns() {
+        let dir = scratch_dir("unmatched", &["a.rs"]);
+        let dir_pattern = glob::escape(dir.to_str().unwrap());
+        let mut shell = Shell::new("sh".to_string(), Vec::new());
+        let expand = |shell: &mut Shell, input: &str| {
+            let command = Command::parse(format!("{dir_pattern}/{input}")).expect("Failed to parse command");
+            shell.expand_words(&command.argv).map(|words| words.len())
+        };
+
+        // Left as they are, unless the option says otherwise
+        assert_eq!(expand(&mut shell, "*.none").ok(), Some(1));
+        assert_eq!(expand(&mut shell, "\"*\".rs").ok(), Some(1));
+        shell.glob_options.nullglob = true;
+        assert_eq!(expand(&mut shell, "*.none").ok(), Some(0));
+        shell.glob_options.failglob = true;
+        assert!(expand(&mut shell, "*.none").is_err());
+        assert_eq!(expand(&mut shell, "*.rs").ok(), Some(1));
+
+        fs::remove_dir_all(dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_for_globbing() {
+        let dir = scratch_dir("for", &["b.rs", "a.rs", "c.txt"]);
+        let mut shell = Shell::new("sh".to_string(), Vec::new());
+
+        let dir_pattern = glob::escape(dir.to_str().unwrap());
+        shell.run_script(&format!("s=; for f in {dir_pattern}/*.rs; do s=\"$s ${{f##*/}}\"; done"));
+        assert_eq!(shell.var("s"), Some(" a.rs b.rs"));
+
+        fs::remove_dir_all(dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn test_shopt() {
+        let mut shell = Shell::new("sh".to_string(), Vec::new());
+
+        assert_eq!(shell.run_script("shopt -s nullglob globstar"), 0);
+        assert!(shell.glob_options.nullglob && shell.glob_options.globstar);
+        assert_eq!(shell.run_script("shopt -q nullglob"), 0);
+        assert_eq!(shell.run_script("shopt -q nullglob dotglob"), 1);
+        assert_eq!(shell.run_script("shopt -u nullglob; shopt -q nullglob"), 1);
+        assert_eq!(shell.run_script("shopt -s nosuchoption"), 1);
+    }
+}
+
+#[cfg(test)]
+mod control_flow {
+    use crate::parser::{Arg, Command};
+    use crate::shell::Shell;
+
+    fn shell() -> Shell {
+        Shell::new("sh".to_string(), Vec::new())
+    }
+
+    /// A command that's just `name` on its own, for building the trees compound commands
+    /// are expected to parse into.
+    pub(super) fn simple(name: &str) -> Command {
+        Command {
+            argv: vec![Arg::Word(name.to_string())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_if() {
+        let mut shell = shell();
+
+        shell.run_script("if false; then x=1; elif ((0)); then x=2; elif true; then x=3; else x=4; fi");
+        assert_eq!(shell.var("x"), Some("3"));
+
+        shell.run_script("if false\nthen\n  x=1\nelse\n  x=5; y=6\nfi");
+        assert_eq!((shell.var("x"), shell.var("y")), (Some("5"), Some("6")));
+
+        // The status is the body's, or 0 if nothing ran
+        assert_eq!(shell.run_script("if true; then false; fi"), 1);
+        assert_eq!(shell.run_script("if false; then true; fi"), 0);
+    }
+
+    #[test]
+    fn test_while() {
+        let mut shell = shell();
+
+        shell.run_script("i=0; s=; while ((i < 4)); do i=$((i + 1)); s=$s$i; done");
+        assert_eq!(shell.var("s"), Some("1234"));
+
+        shell.run_script("i=0; s=; until ((i == 3))\ndo\n  i=$((i + 1))\n  s=$s$i\ndone");
+        assert_eq!(shell.var("s"), Some("123"));
+
+        // The status is the body's last, or 0 if it never ran
+        assert_eq!(shell.run_script("i=0; while ((i++ < 1)); do false; done"), 1);
+        assert_eq!(shell.run_script("while false; do false; done"), 0);
+    }
+
+    #[test]
+    fn test_for() {
+        let mut shell = Shell::new("sh".to_string(), vec!["a b".to_string(), "c".to_string()]);
+
+        shell.run_script("s=; for x in 1 {2,3} \"4 5\"; do s=$s[$x]; done");
+        assert_eq!(shell.var("s"), Some("[1][2][3][4 5]"));
+
+        // The positional parameters, without being split up again
+        shell.run_script("s=; for x; do s=$s[$x]; done");
+        assert_eq!(shell.var("s"), Some("[a b][c]"));
+
+        // The words are expanded once, before the loop starts
+        shell.run_script("y='1 2'; s=; for x in $y; do y=3; s=$s[$x]; done");
+        assert_eq!(shell.var("s"), Some("[1][2]"));
+
+        shell.run_script("s=; for ((i = 0; i < 10; i++)); do ((i % 2)) && continue; ((i > 6)) && break; s=$s$i; done");
+        assert_eq!((shell.var("s"), shell.var("i")), (Some("0246"), Some("8")));
+
+        assert_eq!(shell.run_script("for x in a; do false; done"), 1);
+        assert_eq!(shell.run_script("for x in; do false; done"), 0);
+    }
+
+    #[test]
+    fn test_case() {
+        let mut shell = shell();
+        let script = "case $x in a|b) s=ab;; \"*\") s=star;; c*) s=c;& d) s=${s}d;;& ?) s=${s}?;; *) s=${s}other; esac";
+        let mut run_case = |x: &str| {
+            shell.set_var("x", x.to_string());
+            shell.run_script(&format!("s=; {script}"));
+            shell.var("s").map(str::to_string)
+        };
+
+        assert_eq!(run_case("b").as_deref(), Some("ab"));
+        assert_eq!(run_case("*").as_deref(), Some("star"));
+        assert_eq!(run_case("cat").as_deref(), Some("cdother"));
+        assert_eq!(run_case("d").as_deref(), Some("d?"));
+        assert_eq!(run_case("zz").as_deref(), Some("other"));
+
+        // Patterns from expansions still match as patterns, unless they're quoted
+        shell.run_script("p='*.rs'; case main.rs in \"$p\") s=quoted;; $p) s=unquoted;; esac");
+        assert_eq!(shell.var("s"), Some("unquoted"));
+
+        // The status is the last list's, or 0 if nothing matched
+        assert_eq!(shell.run_script("case a in a) false;; esac"), 1);
+        assert_eq!(shell.run_script("case a in b) false;; esac"), 0);
+
+        // Outside a `case`, these are syntax errors, and nothing on the line runs
+        for script in ["s=ran;; s=after", "s=ran )", "s=ran; s=again;; esac"] {
+            shell.run_script("unset s");
+            assert_eq!(shell.run_script(script), 2, "{script}");
+            assert_eq!(shell.var("s"), None, "{script}");
+        }
+    }
+
+    #[test]
+    fn test_compound_redirects() {
+        let mut shell = shell();
+
+        // Each runs inside `$(...)`, so the test process's own stdin is left alone
+        for (script, expected) in [
+            ("if true; then cat; fi <<< if", "if"),
+            ("i=0; while ((i++ < 2)); do cat; echo $i; done <<< while", "while\n1\n2"),
+            ("for x in a b; do cat; done <<< for", "for"),
+            ("for ((i = 0; i < 1; i++)); do cat; done <<< arith-for", "arith-for"),
+            ("case x in (x) cat;; esac <<< case", "case"),
+            ("{ cat; cat; } <<EOF\ngroup\nEOF", "group"),
+        ] {
+            shell.run_script(&format!("s=$({script}\ncat <<< after)"));
+            assert_eq!(shell.var("s"), Some(format!("{expected}\nafter").as_str()), "{script}");
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut shell = Shell::new("sh".to_string(), vec!["outer".to_string()]);
+
+        shell.run_script("f() { s=\"$# $1 $2\"; }; f a 'b c'");
+        assert_eq!(shell.var("s"), Some("2 a b c"));
+        assert_eq!(shell.positional, vec!["outer".to_string()]);
+
+        // Functions come before builtins
+        shell.run_script("cd() { s=not-cd; }; cd /");
+        assert_eq!(shell.var("s"), Some("not-cd"));
+
+        // Defining one later replaces it, and a function can call itself
+        shell.run_script("function f { s=$s$1; if (($1 > 0)); then f $(($1 - 1)); fi; }; s=; f 3");
+        assert_eq!(shell.var("s"), Some("3210"));
+
+        // Redirections apply to the whole call, run inside `$(...)` to leave the test's stdin alone
+        shell.run_script("f() { cat; echo $1; }; s=$(f a <<< func; cat <<< after)");
+        assert_eq!(shell.var("s"), Some("func\na\nafter"));
+    }
+
+    #[test]
+    fn test_local_and_return() {
+        let mut shell = shell();
+
+        // Locals are seen by the functions called from where they're made, then put back
+        shell.run_script("x=global; y=global; f() { local x=f y; y=set; g; }; g() { s=\"$x $y\"; x=g; }; f");
+        assert_eq!(shell.var("s"), Some("f set"));
+        assert_eq!((shell.var("x"), shell.var("y")), (Some("global"), Some("global")));
+
+        shell.run_script("unset z; f() { local z=1; }; f");
+        assert_eq!(shell.var("z"), None);
+
+        // Assignments before a call only last as long as it does
+        shell.run_script("x=global; f() { s=$x; }; x=call f");
+        assert_eq!((shell.var("s"), shell.var("x")), (Some("call"), Some("global")));
+
+        assert_eq!(shell.run_script("f() { while true; do return 3; done; s=after; }; s=; f"), 3);
+        assert_eq!(shell.var("s"), Some(""));
+        assert_eq!(shell.run_script("f() { false; return; }; f"), 1);
+        assert_eq!(shell.run_script("f() { true; }; false; f"), 0);
+
+        // Neither means anything outside a function, and loops can't be broken from inside one
+        assert_eq!(shell.run_script("return 3"), 1);
+        assert_eq!(shell.run_script("local x"), 1);
+        shell.run_script("f() { break; }; s=; for i in 1 2; do f; s=$s$i; done");
+        assert_eq!(shell.var("s"), Some("12"));
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let mut shell = shell();
+
+        shell.run_script("i=0; s=; while true; do i=$((i + 1)); if ((i == 2)); then continue; fi; if ((i == 4)); then break; fi; s=$s$i; done");
+        assert_eq!(shell.var("s"), Some("13"));
+
+        // Out of more than one loop at once
+        shell.run_script(
+            "i=0; s=; while ((i < 3)); do i=$((i + 1)); j=0; while true; do j=$((j + 1)); \
+             if ((j == 2)); then continue 2; fi; if ((i == 3)); then break 2; fi; s=$s$i$j; done; s=${s}x; done",
+        );
+        assert_eq!(shell.var("s"), Some("1121"));
+
+        // Asking for too many loops leaves all of them
+        shell.run_script("s=; while true; do while true; do break 5; done; s=inner; done; s=${s}out");
+        assert_eq!(shell.var("s"), Some("out"));
+
+        assert_eq!(shell.run_script("i=0; while ((i++ < 1)); do break 0; done"), 1);
+        assert_eq!(shell.run_script("break; echo $?"), 0);
+    }
+
+    #[test]
+    fn test_test() {
+        let mut shell = shell();
+        let file = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let dir = env!("CARGO_MANIFEST_DIR");
+
+        assert_eq!(shell.run_script(&format!("[ -f {file} -a -d {dir} ]")), 0);
+        assert_eq!(shell.run_script(&format!("test -d {file}")), 1);
+        assert_eq!(shell.run_script("[ 3 -lt 10 ] && [ 10 \\< 3 ] && [ abc != abd ]"), 0);
+        assert_eq!(shell.run_script("test ! -z x -o ''"), 0);
+        assert_eq!(shell.run_script("[ \\( a = b \\) -o '' ]"), 1);
+
+        // Taken by how many arguments there are, so these are strings
+        assert_eq!(shell.run_script("test ! = x"), 1);
+        assert_eq!(shell.run_script("[ -z ]"), 0);
+        assert_eq!(shell.run_script("[ ]"), 1);
+
+        assert_eq!(shell.run_script("[ x = x"), 2);
+        assert_eq!(shell.run_script("test x -eq 1"), 2);
+        assert_eq!(shell.run_script("test a b"), 2);
+
+        // Redirections apply to builtins too, run inside `$(...)` to leave the test's stdin alone
+        shell.run_script("s=$(test -t 0 <<< x; echo $?; [ -s /dev/stdin ] <<EOF\ndoc\nEOF\necho $?)");
+        assert_eq!(shell.var("s"), Some("1\n0"));
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let mut shell = shell();
+
+        // Words aren't split, and the right of `==` is a pattern unless it's quoted
+        shell.run_script("x='a b'; s=; [[ $x == a* ]] && s=${s}1; [[ ! $x == \"a*\" ]] && s=${s}2; [[ -n $x && ! -z $x ]] && s=${s}3");
+        assert_eq!(shell.var("s"), Some("123"));
+
+        // Integer comparisons take arithmetic
+        assert_eq!(shell.run_script("i=3; [[ i+1 -eq 4 && ( b < a || 2 -gt 1 ) ]]"), 0);
+        assert_eq!(shell.run_script("[[ '' ]]"), 1);
+
+        shell.run_script("[[ key=value42 =~ ^([a-z]+)=(.*[0-9])$ ]] && s=\"${BASH_REMATCH[1]} ${BASH_REMATCH[2]} ${#BASH_REMATCH[@]}\"");
+        assert_eq!(shell.var("s"), Some("key value42 3"));
+        assert_eq!(shell.var("BASH_REMATCH"), Some("key=value42"));
+
+        // Quoted parts of a regular expression match literally
+        assert_eq!(shell.run_script("[[ axb =~ \"a.b\" ]]"), 1);
+        assert_eq!(shell.var("BASH_REMATCH"), None);
+        assert_eq!(shell.run_script("[[ a =~ ( ]]"), 2);
+    }
 }